@@ -0,0 +1,302 @@
+use std::str::FromStr;
+
+use alloy::primitives::{keccak256, Bytes, FixedBytes, B256, U256};
+use alloy::transports::icp::RpcService;
+use candid::{CandidType, Deserialize, Nat};
+use serde::Serialize;
+
+use crate::job::has_handler;
+use crate::logger::LogLevel;
+use crate::state::{
+    self, BlockRange, ConsensusStrategy, Job, JobStatus, Mode, PollingInterval, State, TaskError,
+    TaskType,
+};
+use crate::storage::{LogEntry, ProcessedLog};
+
+/// The Candid representation of [`state::LogSource`].
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogSource {
+    pub transaction_hash: String,
+    pub log_index: u64,
+}
+
+impl From<state::LogSource> for LogSource {
+    fn from(source: state::LogSource) -> Self {
+        Self {
+            transaction_hash: source.transaction_hash.to_string(),
+            log_index: source.log_index,
+        }
+    }
+}
+
+impl TryFrom<LogSource> for state::LogSource {
+    type Error = String;
+
+    fn try_from(source: LogSource) -> Result<Self, Self::Error> {
+        let transaction_hash = FixedBytes::<32>::from_str(&source.transaction_hash)
+            .map_err(|e| format!("ERROR: invalid transaction hash: {}", e))?;
+        Ok(Self {
+            transaction_hash,
+            log_index: source.log_index,
+        })
+    }
+}
+
+/// The Candid representation of [`state::TopicFilter`].
+///
+/// Values are given as in Solidity for the type of the indexed parameter, e.g. an
+/// address or a decimal number, or as the 32-byte topic in hex.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopicFilter {
+    pub topic1: Option<Vec<String>>,
+    pub topic2: Option<Vec<String>>,
+    pub topic3: Option<Vec<String>>,
+}
+
+impl From<&state::TopicFilter> for TopicFilter {
+    fn from(filter: &state::TopicFilter) -> Self {
+        let to_hex = |topics: &Option<Vec<B256>>| {
+            topics
+                .as_ref()
+                .map(|topics| topics.iter().map(|topic| topic.to_string()).collect())
+        };
+        Self {
+            topic1: to_hex(&filter.topic1),
+            topic2: to_hex(&filter.topic2),
+            topic3: to_hex(&filter.topic3),
+        }
+    }
+}
+
+/// A filtered event and the first topic of its logs.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EventTopic {
+    /// The normalized event signature, e.g. `NewJob(uint256,uint256)`.
+    pub signature: String,
+    /// The keccak256 hash of the signature.
+    pub topic0: String,
+    /// Whether a job handler is registered for the event.
+    pub has_handler: bool,
+}
+
+impl EventTopic {
+    pub fn new(signature: &str) -> Self {
+        let topic0 = keccak256(signature.as_bytes());
+        Self {
+            signature: signature.to_string(),
+            topic0: topic0.to_string(),
+            has_handler: has_handler(&topic0),
+        }
+    }
+}
+
+/// A job that ran out of attempts.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetterJob {
+    pub log_source: LogSource,
+    pub block_number: Option<u64>,
+    pub attempts: u32,
+    pub transaction_hash: Option<String>,
+    pub last_error: Option<String>,
+}
+
+impl DeadLetterJob {
+    pub fn new(source: state::LogSource, job: &Job) -> Self {
+        Self {
+            log_source: source.into(),
+            block_number: job.log.block_number,
+            attempts: job.attempts,
+            transaction_hash: job.transaction_hash.map(|hash| hash.to_string()),
+            last_error: job.last_error.as_ref().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// The current mode of the coprocessor.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ModeStatus {
+    pub mode: Mode,
+    /// The number of jobs that were started but are not confirmed yet, including jobs
+    /// that wait for a retry.
+    pub in_flight_jobs: u64,
+    /// `true` once the canister is draining and all jobs in flight are finished.
+    pub drained: bool,
+}
+
+/// An overview of the canister for monitoring.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CanisterStatus {
+    pub mode: Mode,
+    pub evm_address: Option<String>,
+    /// The nonce of the next new transaction, `None` until synced with the chain.
+    pub next_nonce: Option<u64>,
+    pub last_scraped_block_number: Option<u64>,
+    /// The number of blocks currently queried in a single `eth_getLogs` call.
+    pub block_spread: u64,
+    pub pending_rescans: Vec<BlockRange>,
+    /// The number of jobs that were not confirmed yet.
+    pub pending_jobs: u64,
+    /// The number of jobs that are currently computed.
+    pub active_jobs: u64,
+    /// The number of computed results waiting to be sent.
+    pub queued_results: u64,
+    /// The number of sent transactions that were not mined yet.
+    pub submitted_transactions: u64,
+    pub dead_letter_jobs: u64,
+    pub active_tasks: Vec<TaskType>,
+    pub last_errors: Vec<TaskError>,
+    pub cycle_balance: Nat,
+    pub rpc_service: RpcService,
+    pub rpc_services: Vec<RpcService>,
+    pub consensus: ConsensusStrategy,
+    pub chain_id: u64,
+    pub filter_topics: TopicFilter,
+    pub polling_interval: PollingInterval,
+}
+
+impl CanisterStatus {
+    pub fn new(state: &State, cycle_balance: u128) -> Self {
+        let mut active_tasks: Vec<_> = state.active_tasks.iter().copied().collect();
+        active_tasks.sort();
+        Self {
+            mode: state.mode,
+            evm_address: state
+                .canister_evm_address
+                .map(|address| address.to_string()),
+            next_nonce: state.nonces.next_nonce(),
+            last_scraped_block_number: state.last_scraped_block_number,
+            block_spread: state.block_spread,
+            pending_rescans: state.rescans.iter().copied().collect(),
+            pending_jobs: state.logs_to_process.len() as u64,
+            active_jobs: state.active_jobs.len() as u64,
+            queued_results: state.submission_queue.len() as u64,
+            submitted_transactions: state.submitted_transactions().len() as u64,
+            dead_letter_jobs: state.dead_letter_jobs.len() as u64,
+            active_tasks,
+            last_errors: state.last_errors.values().cloned().collect(),
+            cycle_balance: Nat::from(cycle_balance),
+            rpc_service: state.rpc_service.clone(),
+            rpc_services: state.rpc_services.clone(),
+            consensus: state.consensus,
+            chain_id: state.chain_id,
+            filter_topics: TopicFilter::from(&state.filter_topics),
+            polling_interval: state.polling_interval,
+        }
+    }
+}
+
+/// The progress of the job triggered by a log.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JobInfo {
+    /// The source of the log, including the hash of the transaction that emitted it.
+    pub log_source: LogSource,
+    pub block_number: Option<u64>,
+    pub job_id: Option<Nat>,
+    pub status: JobStatus,
+    pub attempts: u32,
+    /// The ABI-encoded job result.
+    pub result: Option<String>,
+    /// The hash of the latest transaction that sent the job result.
+    pub callback_transaction_hash: Option<String>,
+    pub last_error: Option<String>,
+}
+
+impl JobInfo {
+    pub fn from_job(source: state::LogSource, job: &Job) -> Self {
+        Self {
+            log_source: source.into(),
+            block_number: job.log.block_number,
+            job_id: job.job_id.map(u256_to_nat),
+            status: job.status,
+            attempts: job.attempts,
+            result: job.result.as_ref().map(|result| result.to_string()),
+            callback_transaction_hash: job.transaction_hash.map(|hash| hash.to_string()),
+            last_error: job.last_error.as_ref().map(|e| e.to_string()),
+        }
+    }
+
+    pub fn from_processed_log(source: state::LogSource, processed_log: ProcessedLog) -> Self {
+        Self {
+            log_source: source.into(),
+            block_number: Some(processed_log.block_number),
+            job_id: processed_log
+                .job_id
+                .map(|job_id| u256_to_nat(U256::from_be_bytes(job_id))),
+            status: processed_log.status,
+            attempts: processed_log.attempts,
+            result: processed_log
+                .result
+                .map(|result| Bytes::from(result).to_string()),
+            callback_transaction_hash: processed_log
+                .transaction_hash
+                .map(|hash| FixedBytes::<32>::from(hash).to_string()),
+            last_error: None,
+        }
+    }
+}
+
+/// A page of pending or processed jobs.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct JobPage {
+    pub jobs: Vec<JobInfo>,
+    /// The log source to continue from, `None` if this is the last page.
+    pub next: Option<LogSource>,
+}
+
+/// Selects entries of the canister log. Fields that are `None` match all entries.
+#[derive(CandidType, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    /// Only entries with at least this level are returned.
+    pub min_level: Option<LogLevel>,
+    pub log_source: Option<LogSource>,
+    pub job_id: Option<Nat>,
+    /// Only entries with at least this id are returned.
+    pub from: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+/// An entry of the canister log.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CanisterLogEntry {
+    pub id: u64,
+    /// When the entry was recorded, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub message: String,
+    pub log_source: Option<LogSource>,
+    /// The job id as a decimal number.
+    pub job_id: Option<String>,
+}
+
+impl CanisterLogEntry {
+    pub fn new(id: u64, entry: LogEntry) -> Self {
+        Self {
+            id,
+            timestamp: entry.timestamp,
+            level: entry.level,
+            message: entry.message,
+            log_source: entry.transaction_hash.zip(entry.log_index).map(
+                |(transaction_hash, log_index)| LogSource {
+                    transaction_hash: FixedBytes::<32>::from(transaction_hash).to_string(),
+                    log_index,
+                },
+            ),
+            job_id: entry
+                .job_id
+                .map(|job_id| U256::from_be_bytes(job_id).to_string()),
+        }
+    }
+}
+
+fn u256_to_nat(value: U256) -> Nat {
+    Nat::from_str(&value.to_string()).expect("BUG: U256 is a valid natural number")
+}
+
+/// Converts a Candid `nat` to a `U256`, failing if it has more than 256 bits.
+pub fn nat_to_u256(value: &Nat) -> Result<U256, String> {
+    let bytes = value.0.to_bytes_be();
+    if bytes.len() > 32 {
+        return Err("ERROR: job id does not fit into uint256".to_string());
+    }
+    Ok(U256::from_be_slice(&bytes))
+}
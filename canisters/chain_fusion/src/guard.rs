@@ -1,8 +1,10 @@
-use crate::state::{mutate_state, TaskType};
+use crate::state::{mutate_state, LogSource, TaskType};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum TimerGuardError {
     AlreadyProcessing,
+    /// The task is paused by the current [`crate::state::Mode`].
+    Paused,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -13,6 +15,9 @@ pub struct TimerGuard {
 impl TimerGuard {
     pub fn new(task: TaskType) -> Result<Self, TimerGuardError> {
         mutate_state(|s| {
+            if s.mode.pauses(task) {
+                return Err(TimerGuardError::Paused);
+            }
             if !s.active_tasks.insert(task) {
                 return Err(TimerGuardError::AlreadyProcessing);
             }
@@ -28,3 +33,37 @@ impl Drop for TimerGuard {
         });
     }
 }
+
+/// Guard that ensures a job is only run by one task at a time.
+#[derive(Debug, PartialEq, Eq)]
+pub struct JobGuard {
+    log_source: LogSource,
+}
+
+impl JobGuard {
+    pub fn new(log_source: LogSource) -> Result<Self, TimerGuardError> {
+        mutate_state(|s| {
+            if !s.active_jobs.insert(log_source.clone()) {
+                return Err(TimerGuardError::AlreadyProcessing);
+            }
+            Ok(Self { log_source })
+        })
+    }
+}
+
+impl Drop for JobGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| {
+            s.active_jobs.remove(&self.log_source);
+        });
+    }
+}
+
+/// Guard for endpoints that may only be called by a controller of the canister.
+pub fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("ERROR: caller is not a controller".to_string())
+    }
+}
@@ -1,30 +1,112 @@
 mod calculate_result;
+mod handler;
+mod new_job;
 mod read_result;
 mod submit_result;
+mod track_transaction;
 
+use std::fmt;
+
+use alloy::primitives::{FixedBytes, B256};
 use alloy::rpc::types::Log;
-use read_result::read_result;
-use submit_result::submit_result;
-
-use crate::{
-    job::calculate_result::fibonacci,
-    state::{mutate_state, LogSource},
-    Coprocessor,
-};
-
-pub async fn job(log_source: LogSource, log: Log) {
-    mutate_state(|s| s.record_processed_log(log_source.clone()));
-    // because we deploy the canister with topics only matching
-    // NewJob events we can safely assume that the event is a NewJob.
-    let new_job: Log<Coprocessor::NewJob> = log.log_decode().unwrap();
-    let Coprocessor::NewJob { job_id } = new_job.data();
-    // this calculation would likely exceed an ethereum blocks gas limit
-    // but can easily be calculated on the IC
-    let result = fibonacci(20);
-    // we write the result back to the evm smart contract, creating a signature
-    // on the transaction with chain key ecdsa and sending it to the evm via the
-    // evm rpc canister
-    submit_result(result.to_string(), *job_id).await;
-    // `read_result` demonstrates how to make a `eth_call` via the evm rpc canister
-    read_result(*job_id).await;
+pub use handler::Callback;
+use handler::HandlerRegistry;
+use new_job::NewJobHandler;
+use serde::{Deserialize, Serialize};
+pub use submit_result::{submit_batch, submit_result};
+pub use track_transaction::track_transaction;
+
+use crate::state::{mutate_state, LogSource};
+
+/// The reasons a job can fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobError {
+    /// The log could not be decoded as the expected event or no handler is
+    /// registered for it.
+    Decoding(String),
+    /// The job input decoded from the log is not valid.
+    InvalidInput(String),
+    /// A call to the EVM RPC provider failed.
+    Rpc(String),
+    /// The transaction could not be signed.
+    Signing(String),
+    /// The transaction with the job result could not be sent.
+    Submission(String),
+    /// The transaction with the job result was sent but dropped by the node.
+    TransactionNotFound(FixedBytes<32>),
+    /// The transaction with the job result was mined but reverted.
+    Reverted(FixedBytes<32>),
+}
+
+impl JobError {
+    /// Returns `false` for errors that would occur again on every retry.
+    pub fn is_retriable(&self) -> bool {
+        !matches!(
+            self,
+            JobError::Decoding(_) | JobError::InvalidInput(_) | JobError::Reverted(_)
+        )
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Decoding(e) => write!(f, "failed to decode log: {}", e),
+            JobError::InvalidInput(e) => write!(f, "invalid job input: {}", e),
+            JobError::Rpc(e) => write!(f, "RPC call failed: {}", e),
+            JobError::Signing(e) => write!(f, "failed to sign transaction: {}", e),
+            JobError::Submission(e) => write!(f, "failed to send transaction: {}", e),
+            JobError::TransactionNotFound(tx_hash) => {
+                write!(f, "transaction {} not found", tx_hash)
+            }
+            JobError::Reverted(tx_hash) => write!(f, "transaction {} reverted", tx_hash),
+        }
+    }
+}
+
+thread_local! {
+    static HANDLERS: HandlerRegistry = {
+        let mut registry = HandlerRegistry::default();
+        register_handlers(&mut registry);
+        registry
+    };
+}
+
+/// Registers the handlers for all events the coprocessor reacts to. Add your own
+/// handlers here to serve more events or contracts from the same canister.
+fn register_handlers(registry: &mut HandlerRegistry) {
+    registry.register(NewJobHandler);
+}
+
+/// Returns `true` if a handler is registered for the event with the given topic0.
+pub fn has_handler(topic0: &B256) -> bool {
+    HANDLERS.with(|handlers| handlers.get(topic0).is_some())
+}
+
+/// Returns the types of the indexed parameters of the event with the given topic0, if
+/// a handler is registered for it.
+pub fn indexed_parameters(topic0: &B256) -> Option<&'static [&'static str]> {
+    HANDLERS.with(|handlers| {
+        handlers
+            .get(topic0)
+            .map(|handler| handler.indexed_parameters())
+    })
+}
+
+/// Computes the job result for the log and queues the callback for submission.
+///
+/// Jobs are computed concurrently, while callbacks are sent one at a time in the
+/// order of the queue, so that their nonces are used in sequence.
+pub async fn job(log_source: LogSource, log: Log) -> Result<(), JobError> {
+    mutate_state(|s| s.record_job_attempt(&log_source));
+
+    // events without a registered handler are only scraped after a misconfiguration,
+    // which was warned about when the filter was set.
+    let handler = HANDLERS
+        .with(|handlers| handlers.get_for_log(&log))
+        .ok_or_else(|| JobError::Decoding("no handler registered for event".to_string()))?;
+
+    let callback = handler.handle(&log).await?;
+    mutate_state(|s| s.record_job_computed(&log_source, callback, ic_cdk::api::time()));
+    Ok(())
 }
@@ -1,3 +1,7 @@
+/// The largest input accepted for [`fibonacci`], larger inputs exceed the
+/// instruction limit of a single message.
+pub const MAX_FIBONACCI_INPUT: u64 = 30;
+
 pub fn fibonacci(n: u64) -> u64 {
     if n == 0 {
         0
@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+
+use alloy::primitives::{keccak256, Address, Bytes, B256, U256};
+use alloy::rpc::types::Log;
+use alloy::sol_types::SolValue;
+use serde::{Deserialize, Serialize};
+
+use crate::job::JobError;
+
+/// A boxed future returned by [`JobHandler`] methods.
+pub type HandlerFuture<'a, T> = Pin<Box<dyn Future<Output = T> + 'a>>;
+
+/// The transaction a handler wants to send back to the EVM.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Callback {
+    /// The id of the job, if the event has one. It is recorded with the processed log.
+    pub job_id: Option<U256>,
+    /// The contract to call. Defaults to the configured `coprocessor_evm_address`.
+    pub to: Option<Address>,
+    pub payload: CallbackPayload,
+}
+
+/// The input of the callback transaction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CallbackPayload {
+    /// A job result for the configured callback function. Results can be batched
+    /// with the results of other jobs.
+    Result {
+        /// The ABI-encoded result.
+        result: Bytes,
+        /// The ABI-encoded arguments `(result, job_id)` of the callback function. The
+        /// function selector is prepended when the transaction is sent.
+        arguments: Bytes,
+    },
+    /// The complete ABI-encoded call, including the function selector.
+    Calldata(Bytes),
+}
+
+impl Callback {
+    /// Calls the configured callback function with `(result, job_id)`.
+    ///
+    /// `result` can be any Solidity value, e.g. a `U256`, `Bytes`, a `String`, a
+    /// tuple or a `Vec`, as long as its type matches the first parameter of the
+    /// callback function.
+    pub fn result<T: SolValue>(job_id: U256, result: T) -> Self {
+        Self {
+            job_id: Some(job_id),
+            to: None,
+            payload: CallbackPayload::Result {
+                result: result.abi_encode().into(),
+                arguments: (result, job_id).abi_encode_params().into(),
+            },
+        }
+    }
+
+    /// Returns the ABI-encoded result, if the callback carries one.
+    pub fn result_bytes(&self) -> Option<&Bytes> {
+        match &self.payload {
+            CallbackPayload::Result { result, .. } => Some(result),
+            CallbackPayload::Calldata(_) => None,
+        }
+    }
+
+    /// Returns `true` if the callback can be sent together with other results.
+    pub fn is_batchable(&self) -> bool {
+        self.job_id.is_some()
+            && self.to.is_none()
+            && matches!(self.payload, CallbackPayload::Result { .. })
+    }
+
+    /// Calls `to` with the given calldata, for callbacks that don't follow the
+    /// `(result, job_id)` convention. Such callbacks are never batched.
+    pub fn calldata(job_id: Option<U256>, to: Option<Address>, calldata: Bytes) -> Self {
+        Self {
+            job_id,
+            to,
+            payload: CallbackPayload::Calldata(calldata),
+        }
+    }
+}
+
+/// Runs the coprocessing logic for one kind of event.
+///
+/// A handler decodes the log, computes the result and encodes the callback that
+/// writes the result back to the EVM.
+pub trait JobHandler {
+    /// The signature of the handled event, e.g. `NewJob(uint256)`. Its keccak256
+    /// hash is the first topic of the handled logs.
+    fn event_signature(&self) -> &'static str;
+
+    /// The Solidity types of the indexed parameters of the handled event, in order,
+    /// e.g. `["uint256"]` for `NewJob(uint indexed job_id, uint n)`. Topic filters
+    /// are validated against them. Events without indexed parameters return nothing.
+    fn indexed_parameters(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Decodes the log and computes the callback to send.
+    fn handle<'a>(&'a self, log: &'a Log) -> HandlerFuture<'a, Result<Callback, JobError>>;
+
+    /// Called once the callback transaction was found on chain. Does nothing by default.
+    fn on_confirmed<'a>(&'a self, _log: &'a Log) -> HandlerFuture<'a, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// The registered job handlers keyed by the topic0 of the events they handle.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: BTreeMap<B256, Rc<dyn JobHandler>>,
+}
+
+impl HandlerRegistry {
+    /// Registers a handler for its event.
+    ///
+    /// Panics if a handler for the same event is already registered.
+    pub fn register(&mut self, handler: impl JobHandler + 'static) {
+        let signature = handler.event_signature();
+        assert!(
+            self.handlers
+                .insert(keccak256(signature), Rc::new(handler))
+                .is_none(),
+            "BUG: more than one handler registered for {signature}"
+        );
+    }
+
+    pub fn get(&self, topic0: &B256) -> Option<Rc<dyn JobHandler>> {
+        self.handlers.get(topic0).cloned()
+    }
+
+    /// Returns the handler for the log's event, if any.
+    pub fn get_for_log(&self, log: &Log) -> Option<Rc<dyn JobHandler>> {
+        log.topics().first().and_then(|topic0| self.get(topic0))
+    }
+}
@@ -0,0 +1,74 @@
+use alloy::rpc::types::Log;
+use alloy::sol_types::SolEvent;
+
+use crate::job::calculate_result::{fibonacci, MAX_FIBONACCI_INPUT};
+use crate::job::handler::{Callback, HandlerFuture, JobHandler};
+use crate::job::read_result::read_result;
+use crate::job::JobError;
+use crate::logger::{log, LogLevel};
+use crate::Coprocessor;
+
+/// Handles the `NewJob` event of the `Coprocessor` contract.
+///
+/// The job input is read from the event fields. Inputs that are too large for an
+/// event can instead be stored by the contract and read with an `eth_call` from
+/// `handle`, like `read_result` does for the job result.
+pub struct NewJobHandler;
+
+impl NewJobHandler {
+    fn decode(log: &Log) -> Result<Coprocessor::NewJob, JobError> {
+        let new_job: Log<Coprocessor::NewJob> = log
+            .log_decode()
+            .map_err(|e| JobError::Decoding(e.to_string()))?;
+        Ok(new_job.inner.data)
+    }
+}
+
+impl JobHandler for NewJobHandler {
+    fn event_signature(&self) -> &'static str {
+        Coprocessor::NewJob::SIGNATURE
+    }
+
+    fn indexed_parameters(&self) -> &'static [&'static str] {
+        &["uint256"]
+    }
+
+    fn handle<'a>(&'a self, log: &'a Log) -> HandlerFuture<'a, Result<Callback, JobError>> {
+        Box::pin(async move {
+            let Coprocessor::NewJob { job_id, n } = Self::decode(log)?;
+            let n = u64::try_from(n)
+                .ok()
+                .filter(|n| *n <= MAX_FIBONACCI_INPUT)
+                .ok_or_else(|| {
+                    JobError::InvalidInput(format!(
+                        "n must be at most {MAX_FIBONACCI_INPUT}, got {n}"
+                    ))
+                })?;
+            // this calculation would likely exceed an ethereum blocks gas limit
+            // but can easily be calculated on the IC
+            let result = fibonacci(n);
+            // the result type has to match the first parameter of the configured
+            // `callback_signature`, `callback(string,uint256)` by default
+            Ok(Callback::result(job_id, result.to_string()))
+        })
+    }
+
+    fn on_confirmed<'a>(&'a self, log: &'a Log) -> HandlerFuture<'a, ()> {
+        Box::pin(async move {
+            let Ok(Coprocessor::NewJob { job_id, .. }) = Self::decode(log) else {
+                return;
+            };
+            // `read_result` demonstrates how to make a `eth_call` via the evm rpc canister
+            match read_result::<String>(job_id).await {
+                Ok(result) => log(
+                    LogLevel::Info,
+                    format!("Result of job {}: {}", job_id, result),
+                ),
+                Err(e) => log(
+                    LogLevel::Warn,
+                    format!("Failed to read result of job {}: {}", job_id, e),
+                ),
+            }
+        })
+    }
+}
@@ -1,23 +1,31 @@
-use alloy::{primitives::Uint, providers::ProviderBuilder, transports::icp::IcpConfig};
-use ic_cdk::println;
+use alloy::network::TransactionBuilder;
+use alloy::primitives::U256;
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::rpc::types::TransactionRequest;
+use alloy::sol_types::{SolCall, SolValue};
+use alloy::transports::icp::IcpConfig;
 
-use crate::{
-    state::{read_state, State},
-    Coprocessor,
-};
+use crate::{job::JobError, metrics::observe_rpc, state::read_state, Coprocessor};
 
-pub async fn read_result(job_id: Uint<256, 4>) {
+/// Reads the result of a job with `getResult(uint256)` of the coprocessor contract,
+/// which the results are sent to, and decodes it as `T`.
+///
+/// `T` has to match the return type of `getResult`, which in turn matches the
+/// result type sent with the callback.
+pub async fn read_result<T: SolValue>(job_id: U256) -> Result<T, JobError> {
     let rpc_service = read_state(|s| s.rpc_service.clone());
     let config = IcpConfig::new(rpc_service);
     let provider = ProviderBuilder::new().on_icp(config);
-    let contract_address = read_state(State::get_filter_addresses)[0];
-    let contract = Coprocessor::new(contract_address, provider);
+    let contract_address = read_state(|s| s.coprocessor_evm_address);
 
-    let response = contract.getResult(job_id).call().await;
+    let tx = TransactionRequest::default()
+        .with_to(contract_address)
+        .with_input(Coprocessor::getResultCall { _job_id: job_id }.abi_encode());
+    let output = observe_rpc("eth_call", provider.call(&tx))
+        .await
+        .map_err(|e| JobError::Rpc(e.to_string()))?;
 
-    let result = match response {
-        Ok(result) => result._0,
-        Err(e) => panic!("{}", e.to_string()),
-    };
-    println!("Result: {}", result);
+    <(T,)>::abi_decode_params(&output, true)
+        .map(|(result,)| result)
+        .map_err(|e| JobError::Decoding(e.to_string()))
 }
@@ -1,73 +1,184 @@
-use alloy::primitives::Uint;
+use alloy::network::TransactionBuilder;
+use alloy::primitives::{Address, Bytes, FixedBytes};
+use alloy::providers::utils::Eip1559Estimation;
 use alloy::providers::Provider;
+use alloy::rpc::types::TransactionRequest;
+use alloy::sol_types::SolCall;
 use alloy::{network::EthereumWallet, providers::ProviderBuilder, transports::icp::IcpConfig};
-use ic_cdk::println;
 
-use crate::state::{mutate_state, read_state};
+use crate::job::handler::{Callback, CallbackPayload};
+use crate::job::JobError;
+use crate::metrics::{observe_rpc, observe_signature};
+use crate::nonce::{is_nonce_error, reserve_nonce, resync_nonce};
+use crate::state::{mutate_state, read_state, State, Submission};
 use crate::Coprocessor;
 
-pub async fn submit_result(result: String, job_id: Uint<256, 4>) {
+/// Sends the callback computed by a job handler.
+pub async fn submit_result(callback: &Callback) -> Result<Submission, JobError> {
+    let contract_address = callback
+        .to
+        .unwrap_or_else(|| read_state(|s| s.coprocessor_evm_address));
+    submit_transaction(contract_address, callback_calldata(callback)).await
+}
+
+/// Sends the results of several jobs in one transaction to
+/// `callbackBatch(uint256[],bytes[])` of the coprocessor contract. All callbacks must
+/// be batchable.
+pub async fn submit_batch(callbacks: &[Callback]) -> Result<Submission, JobError> {
+    let coprocessor_evm_address = read_state(|s| s.coprocessor_evm_address);
+    let (job_ids, results): (Vec<_>, Vec<_>) = callbacks
+        .iter()
+        .map(|callback| match &callback.payload {
+            CallbackPayload::Result { result, .. } => (
+                callback
+                    .job_id
+                    .expect("BUG: batched callback without job id"),
+                result.clone(),
+            ),
+            CallbackPayload::Calldata(_) => panic!("BUG: batched callback without result"),
+        })
+        .unzip();
+    let call = Coprocessor::callbackBatchCall {
+        _job_ids: job_ids,
+        _results: results,
+    };
+    submit_transaction(coprocessor_evm_address, call.abi_encode().into()).await
+}
+
+/// Returns the calldata of a single callback transaction.
+fn callback_calldata(callback: &Callback) -> Bytes {
+    match &callback.payload {
+        CallbackPayload::Result { arguments, .. } => {
+            let selector = read_state(State::callback_selector);
+            [selector.as_slice(), arguments].concat().into()
+        }
+        CallbackPayload::Calldata(calldata) => calldata.clone(),
+    }
+}
+
+/// Sends a transaction with a newly reserved nonce.
+async fn submit_transaction(to: Address, calldata: Bytes) -> Result<Submission, JobError> {
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or_else(|| JobError::Signing("EVM address is not initialized yet".to_string()))?;
+    let fees = estimate_fees().await?;
+
+    // nonces are reserved atomically, so concurrent jobs never use the same nonce
+    let nonce = reserve_nonce(evm_address).await?;
+
+    match send_transaction(to, calldata.clone(), nonce, fees).await {
+        Ok(transaction_hash) => {
+            mutate_state(|s| s.nonces.record_sent(nonce, transaction_hash));
+            Ok(Submission {
+                to,
+                input: calldata,
+                nonce,
+                max_fee_per_gas: fees.max_fee_per_gas,
+                max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+                transaction_hashes: vec![transaction_hash],
+                sent_at: ic_cdk::api::time(),
+            })
+        }
+        Err(e) => {
+            mutate_state(|s| s.nonces.release(nonce));
+            // the nonce is out of sync with the chain, e.g. because a transaction was
+            // sent from the canister's address by someone else
+            if is_nonce_error(&e.to_string()) {
+                resync_nonce(evm_address).await?;
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Sends the transaction of a stuck submission again with the same nonce and bumped
+/// fees, so that it replaces the stuck transaction in the mempool.
+pub async fn replace_transaction(submission: &Submission) -> Result<Submission, JobError> {
+    let estimate = estimate_fees().await?;
+    let max_priority_fee_per_gas =
+        bump_fee(submission.max_priority_fee_per_gas).max(estimate.max_priority_fee_per_gas);
+    let max_fee_per_gas = bump_fee(submission.max_fee_per_gas)
+        .max(estimate.max_fee_per_gas)
+        .max(max_priority_fee_per_gas);
+    let fees = Eip1559Estimation {
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+    };
+
+    let transaction_hash = send_transaction(
+        submission.to,
+        submission.input.clone(),
+        submission.nonce,
+        fees,
+    )
+    .await?;
+    let mut transaction_hashes = submission.transaction_hashes.clone();
+    transaction_hashes.push(transaction_hash);
+    Ok(Submission {
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+        transaction_hashes,
+        sent_at: ic_cdk::api::time(),
+        ..submission.clone()
+    })
+}
+
+/// Increases a fee by 12.5%. Nodes only accept a replacement transaction if both fees
+/// are at least 10% higher than those of the replaced transaction.
+fn bump_fee(fee: u128) -> u128 {
+    fee.saturating_add(fee / 8).saturating_add(1)
+}
+
+async fn estimate_fees() -> Result<Eip1559Estimation, JobError> {
+    let rpc_service = read_state(|s| s.rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    observe_rpc("eth_feeHistory", provider.estimate_eip1559_fees(None))
+        .await
+        .map_err(|e| JobError::Rpc(e.to_string()))
+}
+
+/// Signs and sends a transaction from the canister's EVM address and returns its hash.
+async fn send_transaction(
+    to: Address,
+    input: Bytes,
+    nonce: u64,
+    fees: Eip1559Estimation,
+) -> Result<FixedBytes<32>, JobError> {
     // get necessary global state
-    let signer = read_state(|s| s.signer.clone()).unwrap();
-    let evm_address = read_state(|s| s.canister_evm_address).unwrap();
+    let signer = read_state(|s| s.signer.clone())
+        .ok_or_else(|| JobError::Signing("signer is not initialized yet".to_string()))?;
+    let evm_address = read_state(|s| s.canister_evm_address)
+        .ok_or_else(|| JobError::Signing("EVM address is not initialized yet".to_string()))?;
     let wallet = EthereumWallet::new(signer);
     let rpc_service = read_state(|s| s.rpc_service.clone());
     let chain_id = read_state(|s| s.chain_id);
     let config = IcpConfig::new(rpc_service);
-    let provider = ProviderBuilder::new()
-        .with_gas_estimation()
-        .wallet(wallet)
-        .on_icp(config);
-    let contract_address = read_state(|s| s.coprocessor_evm_address);
-    let contract = Coprocessor::new(contract_address, provider.clone());
+    let provider = ProviderBuilder::new().on_icp(config);
 
-    // Attempt to get nonce from thread-local storage
-    let maybe_nonce = read_state(|s| {
-        // If a nonce exists, the next nonce to use is latest nonce + 1
-        s.nonce.map(|nonce| nonce + 1)
-    });
+    let tx = TransactionRequest::default()
+        .with_to(to)
+        .with_input(input)
+        .with_nonce(nonce)
+        .with_from(evm_address)
+        .with_chain_id(chain_id)
+        .with_max_fee_per_gas(fees.max_fee_per_gas)
+        .with_max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
 
-    // If no nonce exists, get it from the provider
-    let nonce = if let Some(nonce) = maybe_nonce {
-        nonce
-    } else {
-        provider
-            .get_transaction_count(evm_address)
-            .await
-            .unwrap_or(0)
-    };
-
-    match contract
-        .callback(result, job_id)
-        .nonce(nonce)
-        .from(evm_address)
-        .chain_id(chain_id)
-        .send()
+    // the transaction is signed separately from sending it, so that the cycles spent
+    // on the signature and on the RPC calls are recorded separately
+    let gas_limit = observe_rpc("eth_estimateGas", provider.estimate_gas(&tx))
         .await
-    {
-        Ok(res) => {
-            let node_hash = *res.tx_hash();
-            let tx_response = contract
-                .provider()
-                .get_transaction_by_hash(node_hash)
-                .await
-                .unwrap();
+        .map_err(|e| JobError::Submission(e.to_string()))?;
+    let envelope = observe_signature(tx.with_gas_limit(gas_limit).build(&wallet))
+        .await
+        .map_err(|e| JobError::Signing(e.to_string()))?;
 
-            match tx_response {
-                Some(_tx) => {
-                    // The transaction has been mined and included in a block, the nonce
-                    // has been consumed. Save it to thread-local storage. Next transaction
-                    // for this address will use a nonce that is = this nonce + 1
-                    mutate_state(|s| {
-                        s.nonce = Some(nonce);
-                    });
-                    println!("Successfully ran job {}, tx: {}", job_id, res.tx_hash())
-                }
-                None => println!("{}", "Could not get transaction.".to_string()),
-            }
-        }
-        Err(e) => {
-            println!("{}", e.to_string())
-        }
+    match observe_rpc(
+        "eth_sendRawTransaction",
+        provider.send_tx_envelope(envelope),
+    )
+    .await
+    {
+        Ok(res) => Ok(*res.tx_hash()),
+        Err(e) => Err(JobError::Submission(e.to_string())),
     }
 }
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use alloy::primitives::FixedBytes;
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::transports::icp::IcpConfig;
+
+use crate::job::submit_result::replace_transaction;
+use crate::job::{JobError, HANDLERS};
+use crate::logger::{log, log_job, LogLevel};
+use crate::logs::schedule_retry;
+use crate::metrics::observe_rpc;
+use crate::state::{mutate_state, read_state, Job, LogSource, Receipt, Submission, TaskType};
+use crate::MAX_TRANSACTION_REPLACEMENTS;
+
+/// Checks whether a callback transaction was mined.
+///
+/// The jobs whose results the transaction carries are confirmed once it is mined, or
+/// moved to the dead letter list if it reverted. Transactions that are not mined
+/// within the configured timeout are replaced with bumped fees, or sent again if the
+/// node dropped them.
+pub async fn track_transaction(
+    submission: Submission,
+    jobs: Vec<(LogSource, Job)>,
+) -> Result<(), JobError> {
+    // any of the transactions sent with the nonce may have been mined
+    for transaction_hash in submission.transaction_hashes.iter().rev() {
+        let Some(receipt) = get_receipt(*transaction_hash).await? else {
+            continue;
+        };
+        mutate_state(|s| s.metrics.record_gas(&receipt));
+        if !receipt.succeeded {
+            for (log_source, _) in &jobs {
+                mutate_state(|s| s.record_job_reverted(log_source, *transaction_hash, receipt));
+                log_job(
+                    LogLevel::Error,
+                    log_source,
+                    format!(
+                        "Transaction {} reverted, moved to the dead letter list",
+                        transaction_hash
+                    ),
+                );
+            }
+            return Ok(());
+        }
+        for (log_source, _) in &jobs {
+            mutate_state(|s| s.record_job_confirmed(log_source, *transaction_hash, receipt));
+            log_job(
+                LogLevel::Info,
+                log_source,
+                format!(
+                    "Successfully ran job, tx: {}, gas used: {}",
+                    transaction_hash, receipt.gas_used
+                ),
+            );
+        }
+        for (_, job) in &jobs {
+            if let Some(handler) = HANDLERS.with(|handlers| handlers.get_for_log(&job.log)) {
+                handler.on_confirmed(&job.log).await;
+            }
+        }
+        return Ok(());
+    }
+
+    let timeout = Duration::from_secs(read_state(|s| s.stuck_transaction_timeout_secs));
+    let stuck_at = submission.sent_at.saturating_add(timeout.as_nanos() as u64);
+    if ic_cdk::api::time() < stuck_at {
+        return Ok(());
+    }
+
+    let transaction_hash = submission.latest_transaction_hash();
+    if !is_transaction_known(transaction_hash).await? {
+        // the nonce is released and the drop counts as a failed attempt, so the jobs
+        // are retried with backoff until they run out of attempts
+        for (log_source, _) in &jobs {
+            mutate_state(|s| s.record_job_dropped(log_source));
+            schedule_retry(log_source, JobError::TransactionNotFound(transaction_hash));
+        }
+        return Err(JobError::TransactionNotFound(transaction_hash));
+    }
+    // no transactions are sent while processing is paused
+    if submission.transaction_hashes.len() > MAX_TRANSACTION_REPLACEMENTS
+        || read_state(|s| s.mode.pauses(TaskType::SubmitResults))
+    {
+        return Ok(());
+    }
+    let replacement = replace_transaction(&submission).await?;
+    log(
+        LogLevel::Warn,
+        format!(
+            "Replaced stuck transaction {} with {}",
+            transaction_hash,
+            replacement.latest_transaction_hash()
+        ),
+    );
+    for (log_source, _) in &jobs {
+        mutate_state(|s| s.record_transaction_replaced(log_source, replacement.clone()));
+    }
+    Ok(())
+}
+
+/// Returns the receipt of the transaction, or `None` if it was not mined yet.
+async fn get_receipt(transaction_hash: FixedBytes<32>) -> Result<Option<Receipt>, JobError> {
+    let rpc_service = read_state(|s| s.rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+
+    let receipt = observe_rpc(
+        "eth_getTransactionReceipt",
+        provider.get_transaction_receipt(transaction_hash),
+    )
+    .await
+    .map_err(|e| JobError::Rpc(e.to_string()))?;
+    Ok(receipt.map(|receipt| Receipt {
+        succeeded: receipt.status(),
+        gas_used: u64::try_from(receipt.gas_used).unwrap_or(u64::MAX),
+        effective_gas_price: u64::try_from(receipt.effective_gas_price).unwrap_or(u64::MAX),
+    }))
+}
+
+/// Checks that the transaction with the given hash is known to the RPC provider.
+async fn is_transaction_known(transaction_hash: FixedBytes<32>) -> Result<bool, JobError> {
+    let rpc_service = read_state(|s| s.rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+
+    match observe_rpc(
+        "eth_getTransactionByHash",
+        provider.get_transaction_by_hash(transaction_hash),
+    )
+    .await
+    {
+        Ok(transaction) => Ok(transaction.is_some()),
+        Err(e) => Err(JobError::Rpc(e.to_string())),
+    }
+}
@@ -1,22 +1,74 @@
+mod endpoints;
 mod guard;
 mod job;
 mod lifecycle;
+mod logger;
 mod logs;
+mod metrics;
+mod nonce;
 mod state;
-// uncomment to enable serving stored assets via http requests
-// mod storage;
+mod storage;
+#[cfg(test)]
+mod test_fixtures;
 
 use std::time::Duration;
 
+use alloy::primitives::{hex, keccak256};
+use alloy::transports::icp::RpcService;
 use alloy::{network::TxSigner, signers::icp::IcpSigner, sol};
-use logs::scrape_eth_logs;
+use candid::Nat;
+use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use ic_cdk::api::management_canister::main::raw_rand;
+use logs::{process_logs, scrape_eth_logs, submit_results, track_transactions};
 
-use lifecycle::InitArg;
-use state::{read_state, State};
+use endpoints::{
+    nat_to_u256, CanisterLogEntry, CanisterStatus, DeadLetterJob, EventTopic, JobInfo, JobPage,
+    LogFilter, LogSource, ModeStatus, TopicFilter,
+};
+use guard::caller_is_controller;
+use lifecycle::{unhandled_events, CanisterArg, UpgradeArg};
+use logger::{log, LogLevel};
+use state::{
+    read_state, ConfigChange, InconsistentResponse, Mode, PollingInterval, State, TaskType,
+};
 
 use crate::state::{initialize_state, mutate_state};
 
-pub const SCRAPING_LOGS_INTERVAL: Duration = Duration::from_secs(60);
+/// How often logs are scraped, unless configured otherwise.
+pub const DEFAULT_SCRAPING_LOGS_INTERVAL: Duration = Duration::from_secs(60);
+/// The number of configuration changes kept in the audit trail.
+pub const MAX_CONFIG_CHANGES: usize = 100;
+/// The number of blocks queried in a single `eth_getLogs` call until the limits of the
+/// providers are known.
+pub const DEFAULT_BLOCK_SPREAD: u64 = 500;
+/// The maximum number of blocks queried in a single `eth_getLogs` call.
+pub const MAX_BLOCK_SPREAD: u64 = 10_000;
+/// The response size limit of `eth_getLogs` calls until a larger one is needed, in bytes.
+pub const DEFAULT_LOGS_RESPONSE_SIZE: u64 = 100_000;
+/// The maximum response size of an HTTPS outcall, in bytes.
+pub const MAX_LOGS_RESPONSE_SIZE: u64 = 2_000_000;
+/// The number of disagreements between log providers kept for review.
+pub const MAX_INCONSISTENT_RESPONSES: usize = 100;
+/// The number of times a job is attempted before it is moved to the dead letter list.
+pub const MAX_JOB_ATTEMPTS: u32 = 5;
+/// The delay before the first retry of a failed job, doubled with every further attempt.
+pub const JOB_RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+pub const JOB_RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+/// The maximum number of jobs computed at the same time, unless configured otherwise.
+pub const DEFAULT_MAX_CONCURRENT_JOBS: u32 = 5;
+/// How often the receipts of sent callback transactions are checked.
+pub const TRACK_TRANSACTIONS_INTERVAL: Duration = Duration::from_secs(20);
+/// Callback transactions that are not mined after this timeout are replaced with bumped
+/// fees, unless configured otherwise.
+pub const DEFAULT_STUCK_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// The number of times a stuck transaction is replaced before we stop bumping its fees.
+pub const MAX_TRANSACTION_REPLACEMENTS: usize = 5;
+/// The maximum number of jobs returned by `list_pending_jobs` and `list_processed_jobs`.
+pub const MAX_JOBS_PER_PAGE: u32 = 100;
+/// The number of entries kept in the canister log.
+pub const MAX_LOG_ENTRIES: u64 = 10_000;
+/// The maximum number of log entries returned by `get_logs`.
+pub const MAX_LOG_ENTRIES_PER_PAGE: u32 = 1_000;
 
 sol!(
     #[sol(rpc)]
@@ -33,15 +85,146 @@ fn setup_timers() {
                 s.signer = Some(signer);
                 s.canister_evm_address = Some(address);
             });
+            // Resume jobs that were still pending when the canister was upgraded.
+            if read_state(State::has_logs_to_process) {
+                ic_cdk::spawn(process_logs());
+            }
+            if read_state(|s| !s.submission_queue.is_empty()) {
+                ic_cdk::spawn(submit_results());
+            }
         })
     });
-    // // Start scraping logs almost immediately after the install, then repeat with the interval.
-    ic_cdk_timers::set_timer(Duration::from_secs(10), || ic_cdk::spawn(scrape_eth_logs()));
+    // Start scraping logs almost immediately after the install, then repeat with the interval.
+    start_log_poller(Duration::from_secs(10));
+    ic_cdk_timers::set_timer_interval(TRACK_TRANSACTIONS_INTERVAL, || {
+        ic_cdk::spawn(track_transactions())
+    });
+}
+
+/// Scrapes logs after `delay` and then again after every run as the polling interval
+/// says, replacing the running poller. The poller is only stopped if scraping is
+/// paused.
+fn start_log_poller(delay: Duration) {
+    if let Some(timer_id) = mutate_state(|s| s.scrape_logs_timer.take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    if read_state(|s| s.mode.pauses(TaskType::ScrapeLogs)) {
+        return;
+    }
+    let timer_id = ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(poll_logs()));
+    mutate_state(|s| s.scrape_logs_timer = Some(timer_id));
+}
+
+/// Scrapes logs and schedules the next run of the poller.
+async fn poll_logs() {
+    // every new log is received as a job
+    let jobs_received = read_state(|s| s.metrics.jobs_received);
+    scrape_eth_logs().await;
+    let found_logs = read_state(|s| s.metrics.jobs_received) > jobs_received;
+    start_log_poller(mutate_state(|s| s.next_polling_delay(found_logs)));
+}
+
+/// Records a configuration change in the audit trail.
+fn record_config_change(change: String) {
+    let change = ConfigChange {
+        timestamp: ic_cdk::api::time(),
+        caller: ic_cdk::caller(),
+        change,
+    };
+    mutate_state(|s| s.record_config_change(change));
+}
+
+/// Applies a configuration change made by a controller at runtime. The change is
+/// validated like an `UpgradeArg` and the log poller is restarted if the filters or
+/// the polling interval changed.
+fn update_config(change: String, arg: UpgradeArg) -> Result<(), String> {
+    let restart_poller = arg.filter_addresses.is_some()
+        || arg.filter_events.is_some()
+        || arg.filter_topics.is_some()
+        || arg.polling_interval.is_some();
+    let events_changed = arg.filter_events.is_some();
+    mutate_state(|s| s.upgrade(arg)).map_err(|e| format!("ERROR: {:?}", e))?;
+    record_config_change(change);
+    if events_changed {
+        warn_about_unhandled_events();
+    }
+    if restart_poller {
+        start_log_poller(Duration::ZERO);
+    }
+    Ok(())
+}
+
+/// Warns about filtered events without a registered job handler. Their logs are
+/// scraped, but the jobs fail.
+fn warn_about_unhandled_events() {
+    let filter_events = read_state(|s| s.filter_events.clone());
+    for event in unhandled_events(&filter_events) {
+        log(
+            LogLevel::Warn,
+            format!(
+                "No job handler is registered for the filtered event {}",
+                event
+            ),
+        );
+    }
+}
+
+/// Switches to the given mode, restarting the log poller and resuming the job
+/// processing as the mode allows.
+fn set_mode(mode: Mode) {
+    if read_state(|s| s.mode) == mode {
+        return;
+    }
+    mutate_state(|s| s.mode = mode);
+    record_config_change(format!("mode = {:?}", mode));
+    start_log_poller(Duration::ZERO);
+    ic_cdk::spawn(process_logs());
+    ic_cdk::spawn(submit_results());
 }
 
 #[ic_cdk::init]
-fn init(arg: InitArg) {
-    initialize_state(state::State::try_from(arg).expect("BUG: failed to initialize canister"));
+fn init(arg: CanisterArg) {
+    match arg {
+        CanisterArg::InitArg(init_arg) => {
+            initialize_state(
+                state::State::try_from(init_arg).expect("BUG: failed to initialize canister"),
+            );
+        }
+        CanisterArg::UpgradeArg(_) => {
+            panic!("ERROR: cannot initialize the canister with an upgrade argument")
+        }
+    }
+    warn_about_unhandled_events();
+    setup_timers();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    read_state(storage::save_state);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(arg: Option<CanisterArg>) {
+    let mut state = storage::load_state();
+    let mut change = None;
+    match arg {
+        Some(CanisterArg::UpgradeArg(upgrade_arg)) => {
+            change = Some(format!("upgrade: {:?}", upgrade_arg));
+            state
+                .upgrade(upgrade_arg)
+                .expect("BUG: failed to upgrade canister");
+        }
+        Some(CanisterArg::InitArg(_)) => {
+            panic!("ERROR: cannot upgrade the canister with an init argument")
+        }
+        None => {}
+    }
+    state.recover_interrupted_jobs();
+    initialize_state(state);
+    if let Some(change) = change {
+        record_config_change(change);
+    }
+    warn_about_unhandled_events();
     setup_timers();
 }
 
@@ -50,24 +233,286 @@ fn get_evm_address() -> Option<String> {
     read_state(|s| s.canister_evm_address.map(|x| x.to_string()))
 }
 
-// uncomment this if you need to serve stored assets from `storage.rs` via http requests
+/// Returns the normalized signatures of the filtered events with their topic0.
+#[ic_cdk::query]
+fn get_event_topics() -> Vec<EventTopic> {
+    read_state(|s| {
+        s.filter_events
+            .iter()
+            .map(|signature| EventTopic::new(signature))
+            .collect()
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_status() -> CanisterStatus {
+    read_state(|s| CanisterStatus::new(s, ic_cdk::api::canister_balance128()))
+}
+
+/// Returns the job with the given id. Pending and failed jobs are returned before
+/// processed ones.
+#[ic_cdk::query]
+fn get_job(job_id: Nat) -> Result<Option<JobInfo>, String> {
+    let job_id = nat_to_u256(&job_id)?;
+    if let Some(job) = read_state(|s| {
+        s.find_job_by_id(job_id)
+            .map(|(source, job)| JobInfo::from_job(source.clone(), job))
+    }) {
+        return Ok(Some(job));
+    }
+    Ok(storage::get_processed_log_by_job_id(job_id.to_be_bytes())
+        .map(|(source, processed_log)| JobInfo::from_processed_log(source, processed_log)))
+}
+
+#[ic_cdk::query]
+fn get_job_by_log_source(log_source: LogSource) -> Result<Option<JobInfo>, String> {
+    let source = state::LogSource::try_from(log_source)?;
+    if let Some(job) = read_state(|s| {
+        s.get_job(&source)
+            .map(|job| JobInfo::from_job(source.clone(), job))
+    }) {
+        return Ok(Some(job));
+    }
+    Ok(storage::get_processed_log(&source)
+        .map(|processed_log| JobInfo::from_processed_log(source, processed_log)))
+}
+
+/// Returns up to `limit` jobs that were not confirmed yet, including those that
+/// failed, ordered by log source, starting at `from`.
+#[ic_cdk::query]
+fn list_pending_jobs(from: Option<LogSource>, limit: u32) -> Result<JobPage, String> {
+    let from = from.map(state::LogSource::try_from).transpose()?;
+    Ok(read_state(|s| {
+        let (page, next) =
+            s.list_pending_jobs(from.as_ref(), limit.min(MAX_JOBS_PER_PAGE) as usize);
+        JobPage {
+            jobs: page
+                .into_iter()
+                .map(|(source, job)| JobInfo::from_job(source.clone(), job))
+                .collect(),
+            next: next.map(LogSource::from),
+        }
+    }))
+}
+
+/// Returns up to `limit` processed jobs ordered by log source, starting at `from`.
+#[ic_cdk::query]
+fn list_processed_jobs(from: Option<LogSource>, limit: u32) -> Result<JobPage, String> {
+    let from = from.map(state::LogSource::try_from).transpose()?;
+    let (page, next) = storage::list_processed_logs(from, limit.min(MAX_JOBS_PER_PAGE) as usize);
+    Ok(JobPage {
+        jobs: page
+            .into_iter()
+            .map(|(source, processed_log)| JobInfo::from_processed_log(source, processed_log))
+            .collect(),
+        next: next.map(LogSource::from),
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_dead_letter_jobs() -> Vec<DeadLetterJob> {
+    read_state(|s| {
+        s.dead_letter_jobs
+            .iter()
+            .map(|(source, job)| DeadLetterJob::new(source.clone(), job))
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn requeue_dead_letter_job(log_source: LogSource) -> Result<(), String> {
+    let source = state::LogSource::try_from(log_source)?;
+    mutate_state(|s| s.requeue_dead_letter_job(&source))?;
+    ic_cdk::spawn(process_logs());
+    Ok(())
+}
+
+/// Scrapes the logs of the blocks `from_block..=to_block` again once the log poller
+/// caught up with the head block, e.g. after a provider missed logs. Logs that were
+/// already processed are skipped. Blocks whose processed logs were pruned cannot be
+/// rescanned.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn rescan(from_block: u64, to_block: u64) -> Result<(), String> {
+    mutate_state(|s| s.add_rescan(from_block, to_block))?;
+    record_config_change(format!("rescan blocks {}..={}", from_block, to_block));
+    ic_cdk::spawn(scrape_eth_logs());
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_rpc_service(rpc_service: RpcService) -> Result<(), String> {
+    update_config(
+        format!("rpc_service = {:?}", rpc_service),
+        UpgradeArg {
+            rpc_service: Some(rpc_service),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_chain_id(chain_id: u64) -> Result<(), String> {
+    update_config(
+        format!("chain_id = {}", chain_id),
+        UpgradeArg {
+            chain_id: Some(chain_id),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_filter_addresses(filter_addresses: Vec<String>) -> Result<(), String> {
+    update_config(
+        format!("filter_addresses = {:?}", filter_addresses),
+        UpgradeArg {
+            filter_addresses: Some(filter_addresses),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_filter_events(filter_events: Vec<String>) -> Result<(), String> {
+    update_config(
+        format!("filter_events = {:?}", filter_events),
+        UpgradeArg {
+            filter_events: Some(filter_events),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_filter_topics(filter_topics: TopicFilter) -> Result<(), String> {
+    update_config(
+        format!("filter_topics = {:?}", filter_topics),
+        UpgradeArg {
+            filter_topics: Some(filter_topics),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_polling_interval(polling_interval: PollingInterval) -> Result<(), String> {
+    update_config(
+        format!("polling_interval = {:?}", polling_interval),
+        UpgradeArg {
+            polling_interval: Some(polling_interval),
+            ..Default::default()
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn set_coprocessor_evm_address(coprocessor_evm_address: String) -> Result<(), String> {
+    update_config(
+        format!("coprocessor_evm_address = {}", coprocessor_evm_address),
+        UpgradeArg {
+            coprocessor_evm_address: Some(coprocessor_evm_address),
+            ..Default::default()
+        },
+    )
+}
 
-// #[ic_cdk::query]
-// fn http_request(req: HttpRequest) -> HttpResponse {
-//     if let Some(asset) = get_asset(&req.path().to_string()) {
-//         let mut response_builder = HttpResponseBuilder::ok();
+/// Stops scraping new logs. Jobs of already scraped logs are still processed.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn pause_scraping() {
+    set_mode(read_state(|s| s.mode).pause_scraping());
+}
 
-//         for (name, value) in asset.headers {
-//             response_builder = response_builder.header(name, value);
-//         }
+/// Stops starting jobs and sending transactions. Logs are still scraped.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn pause_processing() {
+    set_mode(read_state(|s| s.mode).pause_processing());
+}
 
-//         response_builder
-//             .with_body_and_content_length(asset.body)
-//             .build()
-//     } else {
-//         HttpResponseBuilder::not_found().build()
-//     }
-// }
+/// Stops scraping logs and starting new jobs, but finishes the jobs in flight.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn drain() {
+    set_mode(Mode::Draining);
+}
+
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn resume() {
+    set_mode(Mode::Running);
+}
+
+#[ic_cdk::query]
+fn get_mode() -> ModeStatus {
+    read_state(|s| {
+        let in_flight_jobs = s.in_flight_jobs() as u64;
+        ModeStatus {
+            mode: s.mode,
+            in_flight_jobs,
+            drained: s.mode == Mode::Draining && in_flight_jobs == 0,
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_config_changes() -> Vec<ConfigChange> {
+    read_state(|s| s.config_changes.iter().cloned().collect())
+}
+
+/// Returns the most recent disagreements between the log providers, the latest last.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_inconsistent_responses() -> Vec<InconsistentResponse> {
+    read_state(|s| s.inconsistent_responses.iter().cloned().collect())
+}
+
+/// Returns the entries of the canister log matching the filter, oldest first.
+#[ic_cdk::query(guard = "caller_is_controller")]
+fn get_logs(filter: LogFilter) -> Result<Vec<CanisterLogEntry>, String> {
+    logger::get_log_entries(filter)
+}
+
+/// Issues a token to fetch the canister log from `/logs?token=<token>`. The token
+/// replaces any previously issued one.
+#[ic_cdk::update(guard = "caller_is_controller")]
+async fn issue_logs_token() -> Result<String, String> {
+    let (random_bytes,) = raw_rand().await.map_err(|(code, message)| {
+        format!("ERROR: failed to get randomness: {code:?} {message}")
+    })?;
+    let token = hex::encode(random_bytes);
+    mutate_state(|s| s.logs_token_hash = Some(keccak256(token.as_bytes())));
+    record_config_change("logs token issued".to_string());
+    Ok(token)
+}
+
+/// Revokes the token issued by `issue_logs_token`, so that `/logs` is no longer served.
+#[ic_cdk::update(guard = "caller_is_controller")]
+fn revoke_logs_token() {
+    mutate_state(|s| s.logs_token_hash = None);
+    record_config_change("logs token revoked".to_string());
+}
+
+#[ic_cdk::query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    match req.path() {
+        "/metrics" => metrics::serve_metrics(),
+        "/logs" => logger::serve_logs(&req),
+        // uncomment this if you need to serve stored assets from `storage.rs` via http requests
+
+        // path => {
+        //     if let Some(asset) = storage::get_asset(&path.to_string()) {
+        //         let mut response_builder = HttpResponseBuilder::ok();
+
+        //         for (name, value) in asset.headers {
+        //             response_builder = response_builder.header(name, value);
+        //         }
+
+        //         response_builder
+        //             .with_body_and_content_length(asset.body)
+        //             .build()
+        //     } else {
+        //         HttpResponseBuilder::not_found().build()
+        //     }
+        // }
+        _ => HttpResponseBuilder::not_found().build(),
+    }
+}
 
 // Enables Candid export, read more [here](https://internetcomputer.org/docs/current/developer-docs/backend/rust/generating-candid/)
 ic_cdk::export_candid!();
@@ -1,18 +1,422 @@
-use crate::state::{InvalidStateError, State};
-use alloy::primitives::Address;
+use crate::endpoints::TopicFilter;
+use crate::job::{has_handler, indexed_parameters};
+use crate::state::{
+    self, default_block_spread, default_callback_signature, default_logs_response_size,
+    default_max_concurrent_jobs, default_stuck_transaction_timeout_secs, BatchConfig,
+    ConsensusStrategy, FinalityMode, InvalidStateError, PollingInterval, State,
+};
+use alloy::primitives::{keccak256, Address, B256, I256, U256};
 use alloy::transports::icp::RpcService;
 use candid::{CandidType, Deserialize};
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use std::collections::BTreeSet;
 use std::str::FromStr;
 
+/// The argument the canister is installed or upgraded with.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum CanisterArg {
+    InitArg(InitArg),
+    UpgradeArg(UpgradeArg),
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct InitArg {
     pub rpc_service: RpcService,
+    pub rpc_services: Option<Vec<RpcService>>,
+    pub consensus: Option<ConsensusStrategy>,
     pub chain_id: u64,
     pub filter_addresses: Vec<String>,
     pub coprocessor_evm_address: String,
     pub filter_events: Vec<String>,
+    pub filter_topics: Option<TopicFilter>,
     pub ecdsa_key_id: EcdsaKeyId,
+    pub processed_logs_retention_blocks: Option<u64>,
+    pub start_block: Option<u64>,
+    pub finality: Option<FinalityMode>,
+    pub callback_signature: Option<String>,
+    pub stuck_transaction_timeout_secs: Option<u64>,
+    pub max_concurrent_jobs: Option<u32>,
+    pub batch: Option<BatchConfig>,
+    pub polling_interval: Option<PollingInterval>,
+}
+
+/// Configuration changes applied on upgrade. Fields that are `None` keep their
+/// current value.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArg {
+    pub rpc_service: Option<RpcService>,
+    pub rpc_services: Option<Vec<RpcService>>,
+    pub consensus: Option<ConsensusStrategy>,
+    pub chain_id: Option<u64>,
+    pub filter_addresses: Option<Vec<String>>,
+    pub coprocessor_evm_address: Option<String>,
+    pub filter_events: Option<Vec<String>>,
+    pub filter_topics: Option<TopicFilter>,
+    /// `Some(None)` keeps processed logs forever again.
+    pub processed_logs_retention_blocks: Option<Option<u64>>,
+    pub finality: Option<FinalityMode>,
+    pub callback_signature: Option<String>,
+    pub stuck_transaction_timeout_secs: Option<u64>,
+    pub max_concurrent_jobs: Option<u32>,
+    /// `Some(None)` disables batching again.
+    pub batch: Option<Option<BatchConfig>>,
+    pub polling_interval: Option<PollingInterval>,
+}
+
+fn parse_address(address: &str) -> Result<Address, InvalidStateError> {
+    Address::from_str(address)
+        .map_err(|e| InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e)))
+}
+
+/// Normalizes the signatures in `filter_events`, dropping duplicates.
+fn normalize_filter_events(filter_events: &[String]) -> Result<Vec<String>, InvalidStateError> {
+    let mut normalized: Vec<String> = vec![];
+    for event in filter_events {
+        let signature = normalize_event_signature(event)
+            .map_err(|e| InvalidStateError::InvalidEventSignature(format!("{event}: {e}")))?;
+        if !normalized.contains(&signature) {
+            normalized.push(signature);
+        }
+    }
+    Ok(normalized)
+}
+
+/// Returns the events in `filter_events` that no job handler is registered for.
+pub fn unhandled_events(filter_events: &[String]) -> Vec<&String> {
+    filter_events
+        .iter()
+        .filter(|event| !has_handler(&keccak256(event.as_bytes())))
+        .collect()
+}
+
+/// Parses an event signature, e.g. `NewJob(uint indexed job_id, uint n)`, and returns
+/// it in the canonical form its topic0 is the hash of, e.g. `NewJob(uint256,uint256)`.
+///
+/// Parameter names, `indexed` and whitespace are dropped, and the type aliases `uint`,
+/// `int`, `byte`, `fixed` and `ufixed` are replaced by their full names.
+fn normalize_event_signature(signature: &str) -> Result<String, String> {
+    let signature = signature.trim();
+    normalize_signature(signature.strip_prefix("event ").unwrap_or(signature))
+}
+
+/// Parses a function or event signature and returns it in canonical form, see
+/// [`normalize_event_signature`].
+fn normalize_signature(signature: &str) -> Result<String, String> {
+    let (name, parameters) = signature
+        .split_once('(')
+        .ok_or_else(|| "missing parameter list".to_string())?;
+    let name = name.trim();
+    if !is_identifier(name) {
+        return Err(format!("invalid event name {name:?}"));
+    }
+    let parameters = parameters
+        .trim_end()
+        .strip_suffix(')')
+        .ok_or_else(|| "missing closing parenthesis".to_string())?;
+    let types = split_parameters(parameters)?
+        .into_iter()
+        .map(normalize_parameter)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(format!("{name}({})", types.join(",")))
+}
+
+/// Splits a parameter list at the commas outside of tuples.
+fn split_parameters(parameters: &str) -> Result<Vec<&str>, String> {
+    if parameters.trim().is_empty() {
+        return Ok(vec![]);
+    }
+    let mut split = vec![];
+    let mut depth = 0_usize;
+    let mut start = 0;
+    for (i, c) in parameters.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| "unbalanced parentheses".to_string())?
+            }
+            ',' if depth == 0 => {
+                split.push(&parameters[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_string());
+    }
+    split.push(&parameters[start..]);
+    match split.iter().any(|parameter| parameter.trim().is_empty()) {
+        true => Err("empty parameter".to_string()),
+        false => Ok(split),
+    }
+}
+
+/// Normalizes a parameter like `uint indexed job_id` to its type.
+fn normalize_parameter(parameter: &str) -> Result<String, String> {
+    let parameter = parameter.trim();
+    // the type ends at the first whitespace outside of a tuple
+    let mut depth = 0_usize;
+    let type_end = parameter
+        .char_indices()
+        .find(|(_, c)| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            depth == 0 && c.is_whitespace()
+        })
+        .map_or(parameter.len(), |(i, _)| i);
+    let (parameter_type, rest) = parameter.split_at(type_end);
+
+    let mut words = rest.split_whitespace().peekable();
+    words.next_if_eq(&"indexed");
+    if words.next().is_some_and(|name| !is_identifier(name)) || words.next().is_some() {
+        return Err(format!("invalid parameter {parameter:?}"));
+    }
+    normalize_type(parameter_type)
+}
+
+/// Normalizes a Solidity type, e.g. `uint[]` to `uint256[]`.
+fn normalize_type(parameter_type: &str) -> Result<String, String> {
+    let invalid = || format!("invalid type {parameter_type:?}");
+    if let Some(array) = parameter_type.strip_suffix(']') {
+        let (element_type, length) = array.rsplit_once('[').ok_or_else(invalid)?;
+        if !length.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        return Ok(format!("{}[{}]", normalize_type(element_type)?, length));
+    }
+    if let Some(components) = parameter_type
+        .strip_prefix('(')
+        .and_then(|tuple| tuple.strip_suffix(')'))
+    {
+        let components = split_parameters(components)?
+            .into_iter()
+            .map(normalize_parameter)
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(format!("({})", components.join(",")));
+    }
+
+    let parse_size = |digits: &str| {
+        Some(digits)
+            .filter(|digits| !digits.starts_with('0') && digits.chars().all(|c| c.is_ascii_digit()))
+            .and_then(|digits| digits.parse::<u32>().ok())
+    };
+    let size = |prefix: &str| parameter_type.strip_prefix(prefix).and_then(parse_size);
+    // `fixedMxN` and `ufixedMxN` have M bits and N decimals
+    let fixed_size = |prefix: &str| {
+        let (bits, decimals) = parameter_type.strip_prefix(prefix)?.split_once('x')?;
+        Some((parse_size(bits)?, parse_size(decimals)?))
+    };
+    match parameter_type {
+        "address" | "bool" | "string" | "bytes" | "function" => Ok(parameter_type.to_string()),
+        "uint" => Ok("uint256".to_string()),
+        "int" => Ok("int256".to_string()),
+        "byte" => Ok("bytes1".to_string()),
+        "fixed" => Ok("fixed128x18".to_string()),
+        "ufixed" => Ok("ufixed128x18".to_string()),
+        _ => match (size("uint"), size("int"), size("bytes")) {
+            (Some(bits), _, _) | (_, Some(bits), _)
+                if bits % 8 == 0 && (8..=256).contains(&bits) =>
+            {
+                Ok(parameter_type.to_string())
+            }
+            (_, _, Some(length)) if (1..=32).contains(&length) => Ok(parameter_type.to_string()),
+            _ => match fixed_size("fixed").or_else(|| fixed_size("ufixed")) {
+                Some((bits, decimals))
+                    if bits % 8 == 0
+                        && (8..=256).contains(&bits)
+                        && (1..=80).contains(&decimals) =>
+                {
+                    Ok(parameter_type.to_string())
+                }
+                _ => Err(invalid()),
+            },
+        },
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+/// Encodes the topic filter for the indexed parameters of `filter_events`.
+///
+/// Every event has to have an indexed parameter at each filtered position, and the
+/// parameters at the same position have to share their type.
+fn parse_topic_filter(
+    filter: &TopicFilter,
+    filter_events: &[String],
+) -> Result<state::TopicFilter, InvalidStateError> {
+    Ok(state::TopicFilter {
+        topic1: parse_topic(0, &filter.topic1, filter_events)?,
+        topic2: parse_topic(1, &filter.topic2, filter_events)?,
+        topic3: parse_topic(2, &filter.topic3, filter_events)?,
+    })
+}
+
+/// Encodes the values of the topic at `position`, counted from the first indexed
+/// parameter.
+fn parse_topic(
+    position: usize,
+    values: &Option<Vec<String>>,
+    filter_events: &[String],
+) -> Result<Option<Vec<B256>>, InvalidStateError> {
+    let Some(values) = values else {
+        return Ok(None);
+    };
+    let invalid = |reason: String| {
+        InvalidStateError::InvalidTopicFilter(format!("topic{}: {}", position + 1, reason))
+    };
+    if values.is_empty() {
+        return Err(invalid("an empty list matches no logs".to_string()));
+    }
+    let mut types = filter_events
+        .iter()
+        .map(|event| {
+            indexed_parameters(&keccak256(event.as_bytes()))
+                .unwrap_or_default()
+                .get(position)
+                .copied()
+                .ok_or_else(|| invalid(format!("{event} has no such indexed parameter")))
+        })
+        .collect::<Result<BTreeSet<_>, _>>()?;
+    let parameter_type = match types.len() {
+        0 => return Err(invalid("no events to filter".to_string())),
+        1 => types.pop_first().expect("BUG: no parameter type"),
+        _ => {
+            return Err(invalid(format!(
+                "the events have different parameter types {types:?}"
+            )))
+        }
+    };
+    values
+        .iter()
+        .map(|value| {
+            encode_topic(parameter_type, value)
+                .map_err(|e| invalid(format!("invalid {parameter_type} {value}: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+/// Encodes `value` as the topic of an indexed parameter of the given type. Addresses,
+/// integers and booleans are given as in Solidity. Values of other types, including
+/// arrays and tuples whose topic is the hash of the value, and values of any type
+/// given as a 32-byte topic in hex are used as they are.
+fn encode_topic(parameter_type: &str, value: &str) -> Result<B256, String> {
+    if value.len() == 66 {
+        if let Ok(topic) = B256::from_str(value) {
+            return Ok(topic);
+        }
+    }
+    let parameter_type = normalize_type(parameter_type)?;
+    // only elementary integer types have a size after the prefix, e.g. not `uint256[]`
+    let bits = |prefix: &str| {
+        parameter_type
+            .strip_prefix(prefix)
+            .and_then(|bits| bits.parse::<usize>().ok())
+    };
+    match (parameter_type.as_str(), bits("uint"), bits("int")) {
+        ("address", _, _) => Address::from_str(value)
+            .map(Address::into_word)
+            .map_err(|e| e.to_string()),
+        ("bool", _, _) => match value {
+            "true" => Ok(B256::with_last_byte(1)),
+            "false" => Ok(B256::ZERO),
+            _ => Err("expected true or false".to_string()),
+        },
+        (_, Some(bits), _) => {
+            let value = U256::from_str(value).map_err(|e| e.to_string())?;
+            if value.bit_len() > bits {
+                return Err(format!("does not fit into {bits} bits"));
+            }
+            Ok(B256::from(value))
+        }
+        (_, _, Some(bits)) => {
+            let value = I256::from_str(value).map_err(|e| e.to_string())?;
+            // intN ranges from -2^(N-1) to 2^(N-1)-1
+            let limit = U256::from(1) << (bits - 1);
+            let fits = match value.is_negative() {
+                true => value.unsigned_abs() <= limit,
+                false => value.unsigned_abs() < limit,
+            };
+            if !fits {
+                return Err(format!("does not fit into {bits} bits"));
+            }
+            Ok(B256::from(value.into_raw()))
+        }
+        _ => B256::from_str(value).map_err(|e| e.to_string()),
+    }
+}
+
+/// Parses a callback signature like `callback(uint, uint256)` and returns it in the
+/// canonical form its selector is the hash of, e.g. `callback(uint256,uint256)`.
+fn normalize_callback_signature(signature: &str) -> Result<String, InvalidStateError> {
+    let invalid = |reason: &str| {
+        InvalidStateError::InvalidCallbackSignature(format!("{signature}: {reason}"))
+    };
+    let normalized = normalize_signature(signature.trim()).map_err(|e| invalid(&e))?;
+    if normalized.ends_with("()") {
+        return Err(invalid("missing parameters"));
+    }
+    Ok(normalized)
+}
+
+/// Checks that the poller waits at least a second and that the adaptive bounds are
+/// ordered.
+fn validate_polling_interval(polling_interval: &PollingInterval) -> Result<(), InvalidStateError> {
+    let valid = match *polling_interval {
+        PollingInterval::Fixed { secs } => secs > 0,
+        PollingInterval::Adaptive { min_secs, max_secs } => min_secs > 0 && min_secs <= max_secs,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(InvalidStateError::InvalidPollingInterval(format!(
+            "{polling_interval:?}"
+        )))
+    }
+}
+
+/// Checks that the consensus strategy can be reached with the given number of log
+/// providers.
+fn validate_consensus(
+    providers: usize,
+    consensus: &ConsensusStrategy,
+) -> Result<(), InvalidStateError> {
+    let invalid = |reason: &str| {
+        InvalidStateError::InvalidConsensusStrategy(format!(
+            "{reason}: {consensus:?} with {providers} providers"
+        ))
+    };
+    match *consensus {
+        ConsensusStrategy::Equality => Ok(()),
+        ConsensusStrategy::Threshold { total, min } => {
+            if total.is_some_and(|total| total as usize != providers) {
+                return Err(invalid("total does not match the number of providers"));
+            }
+            if min == 0 || min as usize > providers {
+                return Err(invalid("min must be between 1 and the number of providers"));
+            }
+            Ok(())
+        }
+    }
+}
+
+fn parse_addresses(addresses: &[String]) -> Result<Vec<Address>, InvalidStateError> {
+    addresses
+        .iter()
+        .map(|address| parse_address(address))
+        .collect()
 }
 
 impl TryFrom<InitArg> for State {
@@ -21,41 +425,566 @@ impl TryFrom<InitArg> for State {
     fn try_from(
         InitArg {
             rpc_service,
+            rpc_services,
+            consensus,
             chain_id,
             filter_addresses,
             filter_events,
+            filter_topics,
             coprocessor_evm_address,
             ecdsa_key_id,
+            processed_logs_retention_blocks,
+            start_block,
+            finality,
+            callback_signature,
+            stuck_transaction_timeout_secs,
+            max_concurrent_jobs,
+            batch,
+            polling_interval,
         }: InitArg,
     ) -> Result<Self, Self::Error> {
-        let validated_filter_addresses: Vec<Address> = filter_addresses
-            .iter()
-            .map(|address| {
-                Address::from_str(address).map_err(|e| {
-                    InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e))
-                })
-            })
-            .collect::<Result<_, _>>()?;
-
-        let validated_coprocessor_evm_address = Address::from_str(&coprocessor_evm_address)
-            .map_err(|e| {
-                InvalidStateError::InvalidEthereumContractAddress(format!("ERROR: {}", e))
-            })?;
+        let validated_filter_addresses = parse_addresses(&filter_addresses)?;
+        let validated_coprocessor_evm_address = parse_address(&coprocessor_evm_address)?;
+        let filter_events = normalize_filter_events(&filter_events)?;
+        let filter_topics = filter_topics
+            .map(|filter_topics| parse_topic_filter(&filter_topics, &filter_events))
+            .transpose()?
+            .unwrap_or_default();
+        let callback_signature = normalize_callback_signature(
+            &callback_signature.unwrap_or_else(default_callback_signature),
+        )?;
+        let rpc_services = rpc_services.unwrap_or_default();
+        let consensus = consensus.unwrap_or_default();
+        validate_consensus(rpc_services.len().max(1), &consensus)?;
+        let polling_interval = polling_interval.unwrap_or_default();
+        validate_polling_interval(&polling_interval)?;
 
         let state = Self {
             rpc_service,
+            rpc_services,
+            consensus,
+            inconsistent_responses: Default::default(),
             chain_id,
             filter_addresses: validated_filter_addresses,
             filter_events,
+            filter_topics,
             coprocessor_evm_address: validated_coprocessor_evm_address,
             logs_to_process: Default::default(),
-            processed_logs: Default::default(),
+            dead_letter_jobs: Default::default(),
+            processed_logs_retention_blocks,
+            processed_logs_pruned_below: 0,
             active_tasks: Default::default(),
+            active_jobs: Default::default(),
+            max_concurrent_jobs: max_concurrent_jobs.unwrap_or_else(default_max_concurrent_jobs),
+            submission_queue: Default::default(),
+            batch,
             signer: None,
             ecdsa_key_id,
             canister_evm_address: None,
-            nonce: None,
+            nonces: Default::default(),
+            start_block,
+            last_scraped_block_number: None,
+            block_spread: default_block_spread(),
+            logs_response_size: default_logs_response_size(),
+            rescans: Default::default(),
+            finality: finality.unwrap_or_default(),
+            callback_signature,
+            stuck_transaction_timeout_secs: stuck_transaction_timeout_secs
+                .unwrap_or_else(default_stuck_transaction_timeout_secs),
+            config_changes: Default::default(),
+            polling_interval,
+            polling_delay: None,
+            scrape_logs_timer: None,
+            mode: Default::default(),
+            logs_token_hash: None,
+            metrics: Default::default(),
+            last_errors: Default::default(),
         };
         Ok(state)
     }
 }
+
+impl State {
+    /// Applies the configuration changes in `arg`.
+    ///
+    /// All fields are validated before the state is modified, so an invalid
+    /// argument leaves the state untouched.
+    pub fn upgrade(&mut self, arg: UpgradeArg) -> Result<(), InvalidStateError> {
+        let UpgradeArg {
+            rpc_service,
+            rpc_services,
+            consensus,
+            chain_id,
+            filter_addresses,
+            coprocessor_evm_address,
+            filter_events,
+            filter_topics,
+            processed_logs_retention_blocks,
+            finality,
+            callback_signature,
+            stuck_transaction_timeout_secs,
+            max_concurrent_jobs,
+            batch,
+            polling_interval,
+        } = arg;
+
+        let filter_addresses = filter_addresses
+            .as_deref()
+            .map(parse_addresses)
+            .transpose()?;
+        let coprocessor_evm_address = coprocessor_evm_address
+            .as_deref()
+            .map(parse_address)
+            .transpose()?;
+        let filter_events = filter_events
+            .as_deref()
+            .map(normalize_filter_events)
+            .transpose()?;
+        // the topics are validated again against changed events
+        let filter_topics = if filter_events.is_some() || filter_topics.is_some() {
+            let filter_topics =
+                filter_topics.unwrap_or_else(|| TopicFilter::from(&self.filter_topics));
+            let filter_events = filter_events.as_deref().unwrap_or(&self.filter_events);
+            Some(parse_topic_filter(&filter_topics, filter_events)?)
+        } else {
+            None
+        };
+        let callback_signature = callback_signature
+            .as_deref()
+            .map(normalize_callback_signature)
+            .transpose()?;
+        if let Some(polling_interval) = &polling_interval {
+            validate_polling_interval(polling_interval)?;
+        }
+        if rpc_services.is_some() || consensus.is_some() {
+            let providers = rpc_services
+                .as_ref()
+                .map_or(self.rpc_services.len(), Vec::len);
+            validate_consensus(
+                providers.max(1),
+                consensus.as_ref().unwrap_or(&self.consensus),
+            )?;
+        }
+
+        if let Some(rpc_service) = rpc_service {
+            self.rpc_service = rpc_service;
+        }
+        if let Some(rpc_services) = rpc_services {
+            self.rpc_services = rpc_services;
+        }
+        if let Some(consensus) = consensus {
+            self.consensus = consensus;
+        }
+        if let Some(chain_id) = chain_id {
+            // nonces on another chain are unrelated
+            if chain_id != self.chain_id {
+                self.nonces = Default::default();
+            }
+            self.chain_id = chain_id;
+        }
+        if let Some(filter_addresses) = filter_addresses {
+            self.filter_addresses = filter_addresses;
+        }
+        if let Some(coprocessor_evm_address) = coprocessor_evm_address {
+            self.coprocessor_evm_address = coprocessor_evm_address;
+        }
+        if let Some(filter_events) = filter_events {
+            self.filter_events = filter_events;
+        }
+        if let Some(filter_topics) = filter_topics {
+            self.filter_topics = filter_topics;
+        }
+        if let Some(processed_logs_retention_blocks) = processed_logs_retention_blocks {
+            self.processed_logs_retention_blocks = processed_logs_retention_blocks;
+        }
+        if let Some(finality) = finality {
+            self.finality = finality;
+        }
+        if let Some(callback_signature) = callback_signature {
+            self.callback_signature = callback_signature;
+        }
+        if let Some(stuck_transaction_timeout_secs) = stuck_transaction_timeout_secs {
+            self.stuck_transaction_timeout_secs = stuck_transaction_timeout_secs;
+        }
+        if let Some(max_concurrent_jobs) = max_concurrent_jobs {
+            self.max_concurrent_jobs = max_concurrent_jobs;
+        }
+        if let Some(batch) = batch {
+            self.batch = batch;
+        }
+        if let Some(polling_interval) = polling_interval {
+            self.polling_interval = polling_interval;
+            self.polling_delay = None;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::b256;
+
+    use super::*;
+    use crate::test_fixtures::state;
+
+    #[test]
+    fn should_normalize_type_aliases() {
+        assert_eq!(
+            normalize_event_signature("NewJob(uint indexed job_id, uint n)"),
+            Ok("NewJob(uint256,uint256)".to_string())
+        );
+        assert_eq!(
+            normalize_event_signature("event Aliases(int a, byte b, fixed c, ufixed d)"),
+            Ok("Aliases(int256,bytes1,fixed128x18,ufixed128x18)".to_string())
+        );
+        assert_eq!(
+            normalize_event_signature("  Empty( )  "),
+            Ok("Empty()".to_string())
+        );
+    }
+
+    #[test]
+    fn should_normalize_tuples_and_arrays() {
+        assert_eq!(
+            normalize_event_signature("Batch((uint, address)[] indexed items, uint[2][] m)"),
+            Ok("Batch((uint256,address)[],uint256[2][])".to_string())
+        );
+        assert_eq!(
+            normalize_event_signature("Nested(((bool flag, int) inner, bytes32) outer)"),
+            Ok("Nested(((bool,int256),bytes32))".to_string())
+        );
+    }
+
+    #[test]
+    fn should_drop_indexed_and_parameter_names() {
+        assert_eq!(
+            normalize_event_signature("Transfer(address indexed, address indexed to, uint256)"),
+            Ok("Transfer(address,address,uint256)".to_string())
+        );
+        assert_eq!(
+            normalize_event_signature("Named(uint256 $value, bool _flag)"),
+            Ok("Named(uint256,bool)".to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_malformed_signatures() {
+        for signature in [
+            "NewJob",
+            "NewJob(uint256",
+            "NewJob((uint256)",
+            "NewJob(uint256))",
+            "NewJob(uint256,)",
+            "NewJob(,uint256)",
+            "1NewJob(uint256)",
+            "New Job(uint256)",
+            "NewJob(uint256 a b)",
+            "NewJob(uint256 indexed 1a)",
+            "NewJob(uint7)",
+            "NewJob(uint08)",
+            "NewJob(uint264)",
+            "NewJob(bytes33)",
+            "NewJob(uint256[x])",
+            "NewJob(unknown)",
+        ] {
+            assert!(
+                normalize_event_signature(signature).is_err(),
+                "{signature} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn should_split_parameters_outside_of_tuples() {
+        assert_eq!(split_parameters(""), Ok(vec![]));
+        assert_eq!(split_parameters("  "), Ok(vec![]));
+        assert_eq!(
+            split_parameters("uint a, (uint, bool) b,bytes"),
+            Ok(vec!["uint a", " (uint, bool) b", "bytes"])
+        );
+        assert!(split_parameters("uint,").is_err());
+        assert!(split_parameters("uint)").is_err());
+        assert!(split_parameters("(uint").is_err());
+    }
+
+    #[test]
+    fn should_normalize_elementary_types() {
+        for (parameter_type, normalized) in [
+            ("uint", "uint256"),
+            ("int", "int256"),
+            ("uint8", "uint8"),
+            ("int128", "int128"),
+            ("byte", "bytes1"),
+            ("bytes", "bytes"),
+            ("bytes32", "bytes32"),
+            ("string[]", "string[]"),
+            ("address[3]", "address[3]"),
+            ("(uint,bool)", "(uint256,bool)"),
+            ("fixed", "fixed128x18"),
+            ("ufixed", "ufixed128x18"),
+            ("fixed8x1", "fixed8x1"),
+            ("ufixed256x80", "ufixed256x80"),
+        ] {
+            assert_eq!(normalize_type(parameter_type), Ok(normalized.to_string()));
+        }
+        for parameter_type in [
+            "uint0",
+            "uint9",
+            "int512",
+            "uint+8",
+            "bytes0",
+            "uint[",
+            "(uint",
+            "",
+            "fixed128",
+            "fixed7x18",
+            "fixed264x18",
+            "ufixed128x0",
+            "ufixed128x81",
+            "fixed128x018",
+        ] {
+            assert!(
+                normalize_type(parameter_type).is_err(),
+                "{parameter_type} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn should_encode_address_and_bool_topics() {
+        assert_eq!(
+            encode_topic("address", "0x5FbDB2315678afecb367f032d93F642f64180aa3"),
+            Ok(b256!(
+                "0000000000000000000000005fbdb2315678afecb367f032d93f642f64180aa3"
+            ))
+        );
+        assert!(encode_topic("address", "0x5FbDB2315678").is_err());
+        assert_eq!(encode_topic("bool", "true"), Ok(B256::with_last_byte(1)));
+        assert_eq!(encode_topic("bool", "false"), Ok(B256::ZERO));
+        assert!(encode_topic("bool", "yes").is_err());
+    }
+
+    #[test]
+    fn should_encode_integer_topics() {
+        assert_eq!(encode_topic("uint", "1"), Ok(B256::with_last_byte(1)));
+        assert_eq!(encode_topic("uint8", "255"), Ok(B256::with_last_byte(255)));
+        assert_eq!(encode_topic("int256", "-1"), Ok(B256::repeat_byte(0xff)));
+        assert_eq!(
+            encode_topic("int8", "-128"),
+            Ok(b256!(
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff80"
+            ))
+        );
+        assert_eq!(encode_topic("int8", "127"), Ok(B256::with_last_byte(127)));
+    }
+
+    #[test]
+    fn should_reject_integer_topics_that_overflow() {
+        assert!(encode_topic("uint8", "256").is_err());
+        assert!(encode_topic("uint8", "-1").is_err());
+        assert!(encode_topic("int8", "128").is_err());
+        assert!(encode_topic("int8", "-129").is_err());
+        // 2^256
+        assert!(encode_topic(
+            "uint256",
+            "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn should_use_hex_topics_of_arrays_and_tuples() {
+        let topic = b256!("c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+        assert_eq!(encode_topic("uint256[]", &topic.to_string()), Ok(topic));
+        assert_eq!(
+            encode_topic("(uint256,bool)", &topic.to_string()),
+            Ok(topic)
+        );
+        assert_eq!(encode_topic("uint256", &topic.to_string()), Ok(topic));
+        // the elements of an array are not encoded as an integer
+        assert!(encode_topic("uint256[]", "1").is_err());
+    }
+
+    #[test]
+    fn should_parse_topic_filter_of_indexed_parameters() {
+        let filter_events = vec!["NewJob(uint256,uint256)".to_string()];
+        let filter = TopicFilter {
+            topic1: Some(vec!["1".to_string(), B256::repeat_byte(2).to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            parse_topic_filter(&filter, &filter_events),
+            Ok(state::TopicFilter {
+                topic1: Some(vec![B256::with_last_byte(1), B256::repeat_byte(2)]),
+                topic2: None,
+                topic3: None,
+            })
+        );
+        assert_eq!(
+            parse_topic_filter(&TopicFilter::default(), &filter_events),
+            Ok(state::TopicFilter::default())
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_topic_filters() {
+        let filter_events = vec!["NewJob(uint256,uint256)".to_string()];
+        let invalid_filters = [
+            // an empty list would match no logs
+            TopicFilter {
+                topic1: Some(vec![]),
+                ..Default::default()
+            },
+            // `NewJob` has a single indexed parameter
+            TopicFilter {
+                topic2: Some(vec!["1".to_string()]),
+                ..Default::default()
+            },
+            TopicFilter {
+                topic1: Some(vec!["1".to_string(), "one".to_string()]),
+                ..Default::default()
+            },
+        ];
+        for filter in invalid_filters {
+            assert!(
+                matches!(
+                    parse_topic_filter(&filter, &filter_events),
+                    Err(InvalidStateError::InvalidTopicFilter(_))
+                ),
+                "{filter:?} should be rejected"
+            );
+        }
+        let filter = TopicFilter {
+            topic1: Some(vec!["1".to_string()]),
+            ..Default::default()
+        };
+        assert!(parse_topic_filter(&filter, &[]).is_err());
+    }
+
+    #[test]
+    fn should_keep_normalized_signatures_unchanged() {
+        for signature in [
+            "NewJob(uint indexed job_id, uint n)",
+            "Aliases(int a, byte b, fixed c, ufixed d)",
+            "Batch((uint, address)[] indexed items, uint[2][] m)",
+            "Nested(((bool flag, int) inner, bytes32) outer)",
+            "Empty()",
+        ] {
+            let normalized = normalize_event_signature(signature).unwrap();
+            assert_eq!(normalize_event_signature(&normalized), Ok(normalized));
+        }
+    }
+
+    #[test]
+    fn should_reject_invalid_upgrade_without_changing_state() {
+        let mut state = state();
+        let invalid_args = [
+            UpgradeArg {
+                filter_addresses: Some(vec!["0x5FbDB2315678".to_string()]),
+                ..Default::default()
+            },
+            UpgradeArg {
+                coprocessor_evm_address: Some("coprocessor".to_string()),
+                ..Default::default()
+            },
+            UpgradeArg {
+                filter_events: Some(vec!["NewJob(uint256".to_string()]),
+                ..Default::default()
+            },
+            UpgradeArg {
+                callback_signature: Some("callback()".to_string()),
+                ..Default::default()
+            },
+            UpgradeArg {
+                polling_interval: Some(PollingInterval::Adaptive {
+                    min_secs: 10,
+                    max_secs: 5,
+                }),
+                ..Default::default()
+            },
+            UpgradeArg {
+                consensus: Some(ConsensusStrategy::Threshold {
+                    total: None,
+                    min: 2,
+                }),
+                ..Default::default()
+            },
+            UpgradeArg {
+                filter_topics: Some(TopicFilter {
+                    topic1: Some(vec!["-1".to_string()]),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+        for arg in invalid_args {
+            let upgrade_arg = UpgradeArg {
+                chain_id: Some(1),
+                ..arg.clone()
+            };
+            assert!(state.upgrade(upgrade_arg).is_err(), "{arg:?}");
+            assert_eq!(state.chain_id, 31337, "{arg:?}");
+        }
+    }
+
+    #[test]
+    fn should_validate_topic_filter_against_upgraded_events() {
+        let mut state = state();
+        let filter_topics = TopicFilter {
+            topic1: Some(vec!["1".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            state.upgrade(UpgradeArg {
+                filter_topics: Some(filter_topics),
+                ..Default::default()
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            state.filter_topics.topic1,
+            Some(vec![B256::with_last_byte(1)])
+        );
+
+        // the kept filter is checked against the new event, which has no handler
+        let result = state.upgrade(UpgradeArg {
+            filter_events: Some(vec!["Other(uint256)".to_string()]),
+            ..Default::default()
+        });
+        assert!(matches!(
+            result,
+            Err(InvalidStateError::InvalidTopicFilter(_))
+        ));
+        assert_eq!(state.filter_events, vec!["NewJob(uint256,uint256)"]);
+    }
+
+    #[test]
+    fn should_set_keep_and_clear_optional_settings() {
+        let mut state = state();
+        let batch = BatchConfig {
+            max_size: 10,
+            window_secs: 60,
+        };
+        let set = UpgradeArg {
+            processed_logs_retention_blocks: Some(Some(1_000)),
+            batch: Some(Some(batch)),
+            ..Default::default()
+        };
+        assert_eq!(state.upgrade(set), Ok(()));
+        assert_eq!(state.processed_logs_retention_blocks, Some(1_000));
+        assert_eq!(state.batch, Some(batch));
+
+        assert_eq!(state.upgrade(UpgradeArg::default()), Ok(()));
+        assert_eq!(state.processed_logs_retention_blocks, Some(1_000));
+        assert_eq!(state.batch, Some(batch));
+
+        let clear = UpgradeArg {
+            processed_logs_retention_blocks: Some(None),
+            batch: Some(None),
+            ..Default::default()
+        };
+        assert_eq!(state.upgrade(clear), Ok(()));
+        assert_eq!(state.processed_logs_retention_blocks, None);
+        assert_eq!(state.batch, None);
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use alloy::primitives::{keccak256, U256};
+use candid::{CandidType, Deserialize};
+use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use minicbor_derive::{Decode, Encode};
+use serde::Serialize;
+
+use crate::endpoints::{nat_to_u256, CanisterLogEntry, LogFilter};
+use crate::state::{self, read_state, LogSource};
+use crate::storage::{self, LogEntry};
+use crate::{MAX_LOG_ENTRIES, MAX_LOG_ENTRIES_PER_PAGE};
+
+/// The severity of a log entry.
+#[derive(
+    CandidType,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Encode,
+    Decode,
+)]
+pub enum LogLevel {
+    #[n(0)]
+    Debug,
+    #[n(1)]
+    Info,
+    #[n(2)]
+    Warn,
+    #[n(3)]
+    Error,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level.to_lowercase().as_str() {
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warn" => Ok(LogLevel::Warn),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!("ERROR: unknown log level {level}")),
+        }
+    }
+}
+
+/// Records a log entry in the canister log and prints it to the replica log.
+pub fn log(level: LogLevel, message: impl Into<String>) {
+    record(level, None, message.into());
+}
+
+/// Records a log entry about the job triggered by the log with the given source.
+/// The job id is added if it is known.
+pub fn log_job(level: LogLevel, source: &LogSource, message: impl Into<String>) {
+    record(level, Some(source), message.into());
+}
+
+fn record(level: LogLevel, source: Option<&LogSource>, message: String) {
+    let job_id = source.and_then(job_id);
+    match (source, job_id) {
+        (Some(source), Some(job_id)) => {
+            ic_cdk::println!("[{:?}] [job {} {:?}] {}", level, job_id, source, message)
+        }
+        (Some(source), None) => ic_cdk::println!("[{:?}] [{:?}] {}", level, source, message),
+        (None, _) => ic_cdk::println!("[{:?}] {}", level, message),
+    }
+    storage::append_log_entry(
+        LogEntry {
+            timestamp: ic_cdk::api::time(),
+            level,
+            message,
+            transaction_hash: source.map(|source| source.transaction_hash.0),
+            log_index: source.map(|source| source.log_index),
+            job_id: job_id.map(|job_id| job_id.to_be_bytes()),
+        },
+        MAX_LOG_ENTRIES,
+    );
+}
+
+/// Returns the id of the job triggered by the log, if it is known.
+fn job_id(source: &LogSource) -> Option<U256> {
+    read_state(|s| s.get_job(source).and_then(|job| job.job_id)).or_else(|| {
+        storage::get_processed_log(source)
+            .and_then(|processed_log| processed_log.job_id)
+            .map(U256::from_be_bytes)
+    })
+}
+
+/// Returns the log entries matching the filter, oldest first.
+pub fn get_log_entries(filter: LogFilter) -> Result<Vec<CanisterLogEntry>, String> {
+    let log_source = filter
+        .log_source
+        .map(state::LogSource::try_from)
+        .transpose()?;
+    let job_id = filter
+        .job_id
+        .as_ref()
+        .map(nat_to_u256)
+        .transpose()?
+        .map(|job_id| job_id.to_be_bytes::<32>());
+    let limit = filter
+        .limit
+        .unwrap_or(MAX_LOG_ENTRIES_PER_PAGE)
+        .min(MAX_LOG_ENTRIES_PER_PAGE) as usize;
+
+    let entries = storage::get_log_entries(filter.from.unwrap_or_default(), limit, |entry| {
+        filter.min_level.is_none_or(|level| entry.level >= level)
+            && log_source.as_ref().is_none_or(|source| {
+                entry.transaction_hash == Some(source.transaction_hash.0)
+                    && entry.log_index == Some(source.log_index)
+            })
+            && job_id.is_none_or(|job_id| entry.job_id == Some(job_id))
+    });
+    Ok(entries
+        .into_iter()
+        .map(|(id, entry)| CanisterLogEntry::new(id, entry))
+        .collect())
+}
+
+/// Serves the log entries on `/logs` as JSON to holders of the token issued by
+/// `issue_logs_token`.
+///
+/// The entries are filtered with the query parameters `min_level`, `job_id`,
+/// `transaction_hash` and `log_index`, `from` and `limit`, e.g.
+/// `/logs?token=<token>&min_level=warn&limit=100`.
+pub fn serve_logs(req: &HttpRequest) -> HttpResponse {
+    let params = match parse_query(req.url.as_str()) {
+        Ok(params) => params,
+        Err(e) => {
+            return HttpResponseBuilder::bad_request()
+                .with_body_and_content_length(e.into_bytes())
+                .build()
+        }
+    };
+    let authorized = params.get("token").is_some_and(|token| {
+        read_state(|s| s.logs_token_hash == Some(keccak256(token.as_bytes())))
+    });
+    if !authorized {
+        let mut response = HttpResponseBuilder::bad_request()
+            .with_body_and_content_length(b"ERROR: missing or invalid token".to_vec())
+            .build();
+        response.status_code = 403;
+        return response;
+    }
+    match parse_log_filter(&params).and_then(get_log_entries) {
+        Ok(entries) => {
+            let body = serde_json::to_vec(&entries).expect("BUG: failed to encode log entries");
+            HttpResponseBuilder::ok()
+                .header("Content-Type", "application/json")
+                .with_body_and_content_length(body)
+                .build()
+        }
+        Err(e) => HttpResponseBuilder::bad_request()
+            .with_body_and_content_length(e.into_bytes())
+            .build(),
+    }
+}
+
+/// Parses the query parameters of `url`, decoding `+` and percent-encoded bytes.
+fn parse_query(url: &str) -> Result<BTreeMap<String, String>, String> {
+    url.split_once('?')
+        .map(|(_, query)| query)
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .map(|(name, value)| {
+            Ok((
+                decode_query_component(name)?,
+                decode_query_component(value)?,
+            ))
+        })
+        .collect()
+}
+
+fn decode_query_component(component: &str) -> Result<String, String> {
+    let invalid = || format!("ERROR: invalid percent-encoding in {component:?}");
+    let mut bytes = Vec::with_capacity(component.len());
+    let mut input = component.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [
+                    input.next().ok_or_else(invalid)?,
+                    input.next().ok_or_else(invalid)?,
+                ];
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(invalid)?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+fn parse_log_filter(params: &BTreeMap<String, String>) -> Result<LogFilter, String> {
+    let parse_u64 = |name: &str| {
+        params
+            .get(name)
+            .map(|value| {
+                value
+                    .parse::<u64>()
+                    .map_err(|e| format!("ERROR: invalid {name}: {e}"))
+            })
+            .transpose()
+    };
+
+    let log_source = match (params.get("transaction_hash"), parse_u64("log_index")?) {
+        (Some(transaction_hash), Some(log_index)) => Some(crate::endpoints::LogSource {
+            transaction_hash: transaction_hash.to_string(),
+            log_index,
+        }),
+        (None, None) => None,
+        _ => {
+            return Err("ERROR: transaction_hash and log_index must be given together".to_string())
+        }
+    };
+    Ok(LogFilter {
+        min_level: params
+            .get("min_level")
+            .map(|level| LogLevel::from_str(level))
+            .transpose()?,
+        log_source,
+        job_id: params
+            .get("job_id")
+            .map(|job_id| {
+                candid::Nat::from_str(job_id).map_err(|e| format!("ERROR: invalid job_id: {e}"))
+            })
+            .transpose()?,
+        from: parse_u64("from")?,
+        limit: parse_u64("limit")?.map(|limit| limit.min(u32::MAX as u64) as u32),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(url: &str) -> Result<LogFilter, String> {
+        parse_log_filter(&parse_query(url)?)
+    }
+
+    #[test]
+    fn should_parse_log_filter() {
+        assert_eq!(
+            filter(
+                "/logs?min_level=WARN&job_id=42&transaction_hash=0xabc&log_index=3&from=10&limit=5"
+            ),
+            Ok(LogFilter {
+                min_level: Some(LogLevel::Warn),
+                log_source: Some(crate::endpoints::LogSource {
+                    transaction_hash: "0xabc".to_string(),
+                    log_index: 3,
+                }),
+                job_id: Some(candid::Nat::from(42_u32)),
+                from: Some(10),
+                limit: Some(5),
+            })
+        );
+    }
+
+    #[test]
+    fn should_parse_empty_log_filter() {
+        for url in ["/logs", "/logs?", "/logs?token=abc&unknown"] {
+            assert_eq!(filter(url), Ok(LogFilter::default()), "{url}");
+        }
+    }
+
+    #[test]
+    fn should_decode_query_parameters() {
+        let params = parse_query("/logs?token=a%2Bb+c&min%5Flevel=%77arn").unwrap();
+        assert_eq!(params.get("token").map(String::as_str), Some("a+b c"));
+        assert_eq!(
+            parse_log_filter(&params).unwrap().min_level,
+            Some(LogLevel::Warn)
+        );
+        assert_eq!(
+            filter("/logs?limit=%2010").map(|filter| filter.limit),
+            Err("ERROR: invalid limit: invalid digit found in string".to_string())
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_log_filter() {
+        for url in [
+            "/logs?min_level=trace",
+            "/logs?job_id=-1",
+            "/logs?transaction_hash=0xabc",
+            "/logs?log_index=1",
+            "/logs?limit=many",
+            "/logs?token=%2",
+            "/logs?token=%zz",
+            "/logs?token=%ff",
+        ] {
+            assert!(filter(url).is_err(), "{url} should be rejected");
+        }
+    }
+}
@@ -1,65 +1,847 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::time::Duration;
 
-use crate::SCRAPING_LOGS_INTERVAL;
 use crate::{
-    guard::TimerGuard,
-    job::job,
-    state::{mutate_state, read_state, State, TaskType},
+    guard::{JobGuard, TimerGuard},
+    job::{job, submit_batch, submit_result, track_transaction, JobError},
+    logger::{log, log_job, LogLevel},
+    metrics::observe_rpc,
+    state::{
+        mutate_state, read_state, FinalityMode, InconsistentResponse, JobStatus, LogSource, State,
+        TaskType,
+    },
 };
-use alloy::rpc::types::Filter;
-use alloy::{eips::BlockNumberOrTag, providers::Provider};
-use alloy::{providers::ProviderBuilder, rpc::types::Log, transports::icp::IcpConfig};
+use alloy::eips::BlockNumberOrTag;
+use alloy::primitives::{Address, LogData, B256};
+use alloy::providers::Provider;
+use alloy::rpc::types::{Filter, Log};
+use alloy::transports::{Transport, TransportErrorKind, TransportResult};
+use alloy::{providers::ProviderBuilder, transports::icp::IcpConfig};
 
-async fn process_logs() {
+/// Drops reorged logs and starts the jobs that are due.
+pub async fn process_logs() {
     let _guard = match TimerGuard::new(TaskType::ProcessLogs) {
         Ok(guard) => guard,
         Err(_) => return,
     };
 
-    let logs_to_process = read_state(|s| (s.logs_to_process.clone()));
+    if !drop_reorged_logs().await {
+        return;
+    }
+
+    start_jobs();
+}
+
+/// Starts as many due jobs as `max_concurrent_jobs` allows. Every job runs in its own
+/// task, so a slow job does not block the others.
+fn start_jobs() {
+    // no jobs are started while processing is paused, and while the canister drains
+    // `jobs_to_run` only returns jobs that are retried
+    if read_state(|s| s.mode.pauses(TaskType::ProcessLogs)) {
+        return;
+    }
+    let now = ic_cdk::api::time();
+    let jobs_to_run = read_state(|s| s.jobs_to_run(now));
+    let free_job_slots = read_state(State::free_job_slots);
+    for (log_source, job_to_run) in jobs_to_run.into_iter().take(free_job_slots) {
+        ic_cdk::spawn(run_job(log_source, job_to_run.log));
+    }
+}
+
+async fn run_job(log_source: LogSource, log: Log) {
+    let guard = match JobGuard::new(log_source.clone()) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+    match job(log_source.clone(), log).await {
+        Ok(()) => ic_cdk::spawn(submit_results()),
+        Err(e) => {
+            report_error(
+                TaskType::ProcessLogs,
+                Some(&log_source),
+                format!("Job failed: {}", e),
+            );
+            schedule_retry(&log_source, e);
+        }
+    }
+    // the job slot is free again, start the next job
+    drop(guard);
+    start_jobs();
+}
+
+/// Sends the queued callbacks one transaction at a time, so that the transactions'
+/// nonces are reserved and used in the order of the queue. With batching enabled,
+/// the results of several jobs are sent in one transaction.
+pub async fn submit_results() {
+    let _guard = match TimerGuard::new(TaskType::SubmitResults) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    loop {
+        let queued = match mutate_state(|s| s.take_callbacks_to_send(ic_cdk::api::time())) {
+            Ok(queued) if queued.is_empty() => return,
+            Ok(queued) => queued,
+            Err(window_ends_at) => {
+                // send the batch once its window has passed
+                let delay = window_ends_at.saturating_sub(ic_cdk::api::time());
+                ic_cdk_timers::set_timer(Duration::from_nanos(delay), || {
+                    ic_cdk::spawn(submit_results())
+                });
+                return;
+            }
+        };
+
+        // we write the result back to the evm smart contract, creating a signature
+        // on the transaction with chain key ecdsa and sending it to the evm via the
+        // evm rpc canister
+        let result = match (queued.as_slice(), read_state(|s| s.batch)) {
+            ([single], _) => submit_result(&single.callback).await,
+            (_, Some(_)) => {
+                let callbacks: Vec<_> = queued.iter().map(|q| q.callback.clone()).collect();
+                submit_batch(&callbacks).await
+            }
+            (_, None) => unreachable!("only batches contain more than one callback"),
+        };
+        match result {
+            Ok(submission) => {
+                for queued_callback in &queued {
+                    mutate_state(|s| {
+                        s.record_job_submitted(&queued_callback.log_source, submission.clone())
+                    });
+                    log_job(
+                        LogLevel::Info,
+                        &queued_callback.log_source,
+                        format!("Sent result, tx: {}", submission.latest_transaction_hash()),
+                    );
+                }
+            }
+            Err(e) => {
+                for queued_callback in &queued {
+                    report_error(
+                        TaskType::SubmitResults,
+                        Some(&queued_callback.log_source),
+                        format!("Sending result failed: {}", e),
+                    );
+                    schedule_retry(&queued_callback.log_source, e.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Checks the receipts of all sent callback transactions.
+pub async fn track_transactions() {
+    let _guard = match TimerGuard::new(TaskType::TrackTransactions) {
+        Ok(guard) => guard,
+        Err(_) => return,
+    };
+
+    for (submission, jobs) in read_state(State::submitted_transactions) {
+        let transaction_hash = submission.latest_transaction_hash();
+        if let Err(e) = track_transaction(submission, jobs).await {
+            report_error(
+                TaskType::TrackTransactions,
+                None,
+                format!("Tracking transaction {} failed: {}", transaction_hash, e),
+            );
+        }
+    }
 
-    for (event_source, event) in logs_to_process {
-        job(event_source, event).await
+    // jobs whose transaction was dropped are sent again
+    let now = ic_cdk::api::time();
+    if read_state(|s| !s.jobs_to_run(now).is_empty()) {
+        ic_cdk::spawn(process_logs());
     }
 }
 
+/// Logs the error and records it as the last error of the task.
+fn report_error(task: TaskType, log_source: Option<&LogSource>, message: String) {
+    match log_source {
+        Some(log_source) => log_job(LogLevel::Error, log_source, message.clone()),
+        None => log(LogLevel::Error, message.clone()),
+    }
+    mutate_state(|s| s.record_task_error(task, message, ic_cdk::api::time()));
+}
+
+/// Records the failed attempt and schedules a retry with exponential backoff.
+pub fn schedule_retry(log_source: &LogSource, error: JobError) {
+    let now = ic_cdk::api::time();
+    match mutate_state(|s| s.record_job_failure(log_source, error, now)) {
+        Some(delay) => {
+            log_job(
+                LogLevel::Info,
+                log_source,
+                format!("Retrying job in {:?}", delay),
+            );
+            ic_cdk_timers::set_timer(delay, || ic_cdk::spawn(process_logs()));
+        }
+        None => log_job(
+            LogLevel::Warn,
+            log_source,
+            "Job moved to the dead letter list",
+        ),
+    }
+}
+
+/// Compares the block hash of every pending log with the canonical chain and drops the
+/// logs whose block was reorged. The canonical hash of a block is the one returned by
+/// at least `min_agreeing_providers` log providers.
+///
+/// Returns `false` if the block hashes could not be verified, in which case no logs
+/// should be processed yet.
+async fn drop_reorged_logs() -> bool {
+    // finalized blocks cannot be reorged
+    if read_state(|s| s.finality) == FinalityMode::Finalized {
+        return true;
+    }
+    let (rpc_services, min_agreeing) =
+        read_state(|s| (s.log_rpc_services(), s.min_agreeing_providers()));
+    let providers: Vec<_> = rpc_services
+        .into_iter()
+        .map(|rpc_service| ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service)))
+        .collect();
+
+    let pending_blocks: BTreeMap<u64, Vec<(LogSource, Option<B256>)>> = read_state(|s| {
+        let mut pending_blocks: BTreeMap<u64, Vec<_>> = BTreeMap::new();
+        // jobs whose result was already sent are not dropped anymore
+        let pending_jobs = s
+            .logs_to_process
+            .iter()
+            .filter(|(_, job)| job.status == JobStatus::Pending);
+        for (source, job) in pending_jobs {
+            pending_blocks
+                .entry(job.log.block_number.unwrap_or_default())
+                .or_default()
+                .push((source.clone(), job.log.block_hash));
+        }
+        pending_blocks
+    });
+
+    for (block_number, logs) in pending_blocks {
+        let mut block_hashes = Vec::with_capacity(providers.len());
+        for (index, provider) in providers.iter().enumerate() {
+            match observe_rpc(
+                "eth_getBlockByNumber",
+                provider.get_block_by_number(BlockNumberOrTag::Number(block_number), false),
+            )
+            .await
+            {
+                Ok(block) => block_hashes.push(block.map(|block| block.header.hash)),
+                Err(e) => log(
+                    LogLevel::Warn,
+                    format!(
+                        "Log provider {} failed to get block {}: {}",
+                        index, block_number, e
+                    ),
+                ),
+            }
+        }
+        let Some(canonical_hash) = agreed_value(block_hashes, min_agreeing) else {
+            report_error(
+                TaskType::ProcessLogs,
+                None,
+                format!("No quorum of log providers for block {}", block_number),
+            );
+            return false;
+        };
+        for (source, block_hash) in logs {
+            // the job may have been started while the block hashes were fetched, in
+            // which case it is kept
+            if block_hash != canonical_hash && mutate_state(|s| s.record_reorged_log(&source)) {
+                log_job(
+                    LogLevel::Warn,
+                    &source,
+                    format!("Dropped log from reorged block {}", block_number),
+                );
+            }
+        }
+    }
+    true
+}
+
+/// Returns the value returned by at least `min_agreeing` providers, or `None` if no
+/// value was returned by enough providers.
+fn agreed_value<T: PartialEq>(values: Vec<T>, min_agreeing: usize) -> Option<T> {
+    let mut counts: Vec<(T, usize)> = vec![];
+    for value in values {
+        match counts.iter_mut().find(|(counted, _)| *counted == value) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((value, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .find(|(_, count)| *count >= min_agreeing.max(1))
+        .map(|(value, _)| value)
+}
+
+/// Returns the highest of the head blocks reported by the providers that at least
+/// `min_agreeing` of them reached, or `None` if fewer providers answered. A provider
+/// that lags behind or reports blocks that do not exist yet is outvoted.
+fn agreed_head_block_number(mut head_blocks: Vec<u64>, min_agreeing: usize) -> Option<u64> {
+    head_blocks.sort_unstable_by(|a, b| b.cmp(a));
+    head_blocks.get(min_agreeing.max(1) - 1).copied()
+}
+
+/// Returns the number of the most recent block whose logs may be processed according
+/// to the configured finality mode, as agreed by the log providers.
+async fn head_block_number(finality: FinalityMode) -> Result<u64, String> {
+    let (rpc_services, min_agreeing) =
+        read_state(|s| (s.log_rpc_services(), s.min_agreeing_providers()));
+    let mut head_blocks = Vec::with_capacity(rpc_services.len());
+    let mut errors = vec![];
+    for (index, rpc_service) in rpc_services.into_iter().enumerate() {
+        let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+        match provider_head_block_number(&provider, finality).await {
+            Ok(head_block) => head_blocks.push(head_block),
+            Err(e) => errors.push(format!("log provider {}: {}", index, e)),
+        }
+    }
+    let answered = head_blocks.len();
+    agreed_head_block_number(head_blocks, min_agreeing).ok_or_else(|| {
+        format!(
+            "{} of the required {} providers answered: {}",
+            answered,
+            min_agreeing,
+            errors.join(", ")
+        )
+    })
+}
+
+/// Returns the number of the most recent block whose logs may be processed according
+/// to the configured finality mode, as reported by a single provider.
+async fn provider_head_block_number<P, T>(
+    provider: &P,
+    finality: FinalityMode,
+) -> TransportResult<u64>
+where
+    P: Provider<T>,
+    T: Transport + Clone,
+{
+    let tag = match finality {
+        FinalityMode::Latest { confirmations } => {
+            let latest = observe_rpc("eth_blockNumber", provider.get_block_number()).await?;
+            return Ok(latest.saturating_sub(confirmations));
+        }
+        FinalityMode::Safe => BlockNumberOrTag::Safe,
+        FinalityMode::Finalized => BlockNumberOrTag::Finalized,
+    };
+    let block = observe_rpc(
+        "eth_getBlockByNumber",
+        provider.get_block_by_number(tag, false),
+    )
+    .await?
+    .ok_or_else(|| TransportErrorKind::custom_str(&format!("no {tag} block found")))?;
+    Ok(block.header.number)
+}
+
 pub async fn scrape_eth_logs() {
     let _guard = match TimerGuard::new(TaskType::ScrapeLogs) {
         Ok(guard) => guard,
         Err(_) => return,
     };
     let rpc_service = read_state(|s| s.rpc_service.clone());
-    let config = IcpConfig::new(rpc_service).set_max_response_size(100_000);
-    let provider = ProviderBuilder::new().on_icp(config);
-    let addresses = read_state(State::get_filter_addresses);
-    let events = read_state(State::get_filter_events);
-
-    // This callback will be called every time new logs are received
-    let callback = |incoming_logs: Vec<Log>| {
-        for log in incoming_logs.iter() {
-            mutate_state(|s| s.record_log_to_process(log));
-        }
-        if read_state(State::has_logs_to_process) {
-            ic_cdk_timers::set_timer(
-                Duration::from_secs(0),
-                move || ic_cdk::spawn(process_logs()),
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let filter = read_state(State::log_filter);
+
+    let finality = read_state(|s| s.finality);
+    let head_block = match head_block_number(finality).await {
+        Ok(block_number) => {
+            mutate_state(|s| s.metrics.head_block_number = Some(block_number));
+            block_number
+        }
+        Err(e) => {
+            report_error(
+                TaskType::ScrapeLogs,
+                None,
+                format!("Failed to get the {:?} head block number: {}", finality, e),
             );
+            return;
         }
     };
+    // If neither a cursor nor a start block is set we only scrape new blocks.
+    let from_block = read_state(State::next_block_to_scrape).unwrap_or(head_block);
+
+    // Walk forward from the cursor until we caught up with the head block. Logs from
+    // blocks after the head are picked up by a later run.
+    let caught_up = backfill(&filter, from_block, head_block, |s, to_block| {
+        s.last_scraped_block_number = Some(to_block)
+    })
+    .await;
+
+    // Processed logs are only pruned relative to the head block once every block
+    // before it was scraped, so that no log older than the retention is recorded later.
+    // Blocks requested by controllers are scraped again once we caught up.
+    if caught_up {
+        mutate_state(|s| s.prune_processed_logs(head_block));
+        while let Some(range) = read_state(|s| s.rescans.front().copied()) {
+            log(
+                LogLevel::Info,
+                format!(
+                    "Rescanning blocks {}..={}",
+                    range.from_block, range.to_block
+                ),
+            );
+            let completed = backfill(
+                &filter,
+                range.from_block,
+                range.to_block,
+                State::record_rescan_progress,
+            )
+            .await;
+            if !completed {
+                break;
+            }
+        }
+    }
 
-    let filter = Filter::new()
-        .address(addresses)
-        // By specifying an `event` or `event_signature` we listen for a specific event of the
-        // contract. In this case the `Transfer(address,address,uint256)` event.
-        // .event(Coprocessor::NewJob::SIGNATURE)
-        .events(events)
-        .from_block(BlockNumberOrTag::Latest);
-
-    // Initialize the poller and start watching
-    // `with_poll_interval` (optional) is used to set the interval between polls, defaults to 7 seconds
-    let poller = provider.watch_logs(&filter).await.unwrap();
-    let _timer_id = poller
-        .with_poll_interval(SCRAPING_LOGS_INTERVAL)
-        .start(callback)
-        .unwrap();
+    if read_state(State::has_logs_to_process) {
+        ic_cdk_timers::set_timer(
+            Duration::from_secs(0),
+            move || ic_cdk::spawn(process_logs()),
+        );
+    }
+
+    update_eth_balance(&provider).await;
+}
+
+/// Scrapes the logs matching `filter` of the blocks `from_block..=to_block` in chunks
+/// of `block_spread` blocks. A chunk that the providers reject as too large is halved
+/// and retried, and the spread grows again with every successful call.
+///
+/// The logs of every chunk are recorded together with `record_progress`, which is
+/// called with the last block of the chunk. Returns `false` if not all blocks were
+/// scraped, because a call failed or the filters were changed.
+async fn backfill(
+    filter: &Filter,
+    from_block: u64,
+    to_block: u64,
+    record_progress: impl Fn(&mut State, u64),
+) -> bool {
+    let mut from_block = from_block;
+    while from_block <= to_block {
+        let spread = read_state(|s| s.block_spread);
+        let chunk_end = to_block.min(from_block.saturating_add(spread - 1));
+        let chunk = filter.clone().from_block(from_block).to_block(chunk_end);
+
+        let logs = match get_logs_with_consensus(&chunk, from_block, chunk_end).await {
+            Ok(logs) => {
+                mutate_state(State::grow_block_spread);
+                logs
+            }
+            Err(GetLogsError::RangeTooLarge(e))
+                if read_state(|s| s.can_shrink_block_spread(chunk_end - from_block + 1)) =>
+            {
+                mutate_state(|s| s.shrink_block_spread(chunk_end - from_block + 1));
+                let (spread, response_size) =
+                    read_state(|s| (s.block_spread, s.logs_response_size));
+                log(
+                    LogLevel::Info,
+                    format!(
+                        "Blocks {}..={} were rejected as too large, retrying with {} blocks \
+                         and a response size limit of {} bytes: {}",
+                        from_block, chunk_end, spread, response_size, e
+                    ),
+                );
+                continue;
+            }
+            Err(e) => {
+                report_error(
+                    TaskType::ScrapeLogs,
+                    None,
+                    format!(
+                        "Failed to get logs for blocks {}..={}: {}",
+                        from_block, chunk_end, e
+                    ),
+                );
+                return false;
+            }
+        };
+        // the filters were changed by a controller while we were waiting for the logs,
+        // the restarted poller scrapes the range again with the new filters
+        if read_state(State::log_filter) != *filter {
+            return false;
+        }
+        log(
+            LogLevel::Debug,
+            format!(
+                "Scraped {} logs from blocks {}..={}",
+                logs.len(),
+                from_block,
+                chunk_end
+            ),
+        );
+        // Logs and progress are updated together, so a range is never recorded twice.
+        mutate_state(|s| {
+            for log in logs.iter() {
+                s.record_log_to_process(log);
+            }
+            record_progress(s, chunk_end);
+        });
+        from_block = chunk_end + 1;
+    }
+    true
+}
+
+/// An error of an `eth_getLogs` call.
+enum GetLogsError {
+    /// The block range or the response exceeded the limits of a provider.
+    RangeTooLarge(String),
+    Failed(String),
+}
+
+impl fmt::Display for GetLogsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetLogsError::RangeTooLarge(e) | GetLogsError::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Returns whether the error says that the block range or the response of an
+/// `eth_getLogs` call was too large, e.g. `query returned more than 10000 results`
+/// or `Log response size exceeded`, or that the HTTPS outcall failed with
+/// `Http body exceeds size limit`. Other errors, such as exceeded rate limits or
+/// quotas, must not shrink the range or raise the response size limit.
+fn is_range_too_large(error: &str) -> bool {
+    let error = error.to_lowercase();
+    [
+        "query returned more than",
+        "block range is too large",
+        "block range is too wide",
+        "block range too large",
+        "response size exceeded",
+        "body exceeds size limit",
+    ]
+    .iter()
+    .any(|pattern| error.contains(pattern))
+}
+
+/// Queries the logs from every log provider and returns the logs returned by at least
+/// `min_agreeing_providers` of them.
+///
+/// A log returned by too few providers is dropped if the other providers are enough to
+/// reject it. Otherwise, or if too few providers answered, an error is returned and the
+/// range is scraped again by a later run. Every disagreement is recorded for review.
+async fn get_logs_with_consensus(
+    filter: &Filter,
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<Log>, GetLogsError> {
+    let (rpc_services, min_agreeing, response_size) = read_state(|s| {
+        (
+            s.log_rpc_services(),
+            s.min_agreeing_providers(),
+            s.logs_response_size,
+        )
+    });
+
+    let mut responses = Vec::with_capacity(rpc_services.len());
+    let mut errors = vec![];
+    let mut range_too_large = false;
+    // providers are named by their index in `rpc_services` because custom providers
+    // contain URLs and headers with API keys, which must not end up in the public log
+    for (index, rpc_service) in rpc_services.into_iter().enumerate() {
+        let config = IcpConfig::new(rpc_service).set_max_response_size(response_size);
+        let provider = ProviderBuilder::new().on_icp(config);
+        match observe_rpc("eth_getLogs", provider.get_logs(filter)).await {
+            Ok(logs) => responses.push(logs),
+            Err(e) => {
+                log(
+                    LogLevel::Warn,
+                    format!("Log provider {} failed to get logs: {}", index, e),
+                );
+                let error = format!("log provider {}: {}", index, e);
+                range_too_large |= is_range_too_large(&error);
+                errors.push(error);
+            }
+        }
+    }
+    if responses.len() < min_agreeing {
+        let message = format!(
+            "{} of the required {} providers answered: {}",
+            responses.len(),
+            min_agreeing,
+            errors.join(", ")
+        );
+        return Err(if range_too_large {
+            GetLogsError::RangeTooLarge(message)
+        } else {
+            GetLogsError::Failed(message)
+        });
+    }
+
+    let LogsConsensus {
+        accepted,
+        disagreements,
+        undecided,
+    } = logs_consensus(&responses, min_agreeing);
+    if !disagreements.is_empty() {
+        let description = disagreements.join(", ");
+        log(
+            LogLevel::Warn,
+            format!(
+                "Log providers disagree on blocks {}..={}: {}",
+                from_block, to_block, description
+            ),
+        );
+        mutate_state(|s| {
+            s.record_inconsistent_response(InconsistentResponse {
+                timestamp: ic_cdk::api::time(),
+                from_block,
+                to_block,
+                description: description.clone(),
+            })
+        });
+        if undecided {
+            return Err(GetLogsError::Failed(format!(
+                "no quorum of providers: {}",
+                description
+            )));
+        }
+    }
+    Ok(accepted)
+}
+
+/// The outcome of comparing the `eth_getLogs` responses of the log providers.
+struct LogsConsensus {
+    /// The logs returned by enough providers, ordered by block and log index.
+    accepted: Vec<Log>,
+    /// A description of every log returned by too few providers.
+    disagreements: Vec<String>,
+    /// Whether too few providers agree on the existence of some log to decide on it.
+    undecided: bool,
+}
+
+/// The fields that providers have to agree on for a log. Other fields, such as
+/// `block_timestamp`, `transaction_index` or `removed`, are filled in differently by
+/// honest providers.
+type ConsensusKey<'a> = (
+    Option<B256>,
+    Option<u64>,
+    Option<B256>,
+    Option<u64>,
+    &'a Address,
+    &'a LogData,
+);
+
+fn consensus_key(log: &Log) -> ConsensusKey<'_> {
+    (
+        log.transaction_hash,
+        log.log_index,
+        log.block_hash,
+        log.block_number,
+        &log.inner.address,
+        &log.inner.data,
+    )
+}
+
+/// Accepts the logs returned by at least `min_agreeing` of the `responses` and
+/// rejects those that at least `min_agreeing` responses do not contain.
+fn logs_consensus(responses: &[Vec<Log>], min_agreeing: usize) -> LogsConsensus {
+    // count the responses containing each log, a log repeated in the same response
+    // is counted once
+    let mut counts: Vec<(&Log, usize)> = vec![];
+    for logs in responses {
+        for (i, log) in logs.iter().enumerate() {
+            let key = consensus_key(log);
+            if logs[..i].iter().any(|other| consensus_key(other) == key) {
+                continue;
+            }
+            match counts
+                .iter_mut()
+                .find(|(counted, _)| consensus_key(counted) == key)
+            {
+                Some((_, count)) => *count += 1,
+                None => counts.push((log, 1)),
+            }
+        }
+    }
+
+    let mut accepted = vec![];
+    let mut disagreements = vec![];
+    let mut undecided = false;
+    for (log, count) in counts {
+        if count >= min_agreeing {
+            accepted.push(log.clone());
+            continue;
+        }
+        disagreements.push(format!(
+            "log {:?}/{:?} returned by {} of {} providers",
+            log.transaction_hash,
+            log.log_index,
+            count,
+            responses.len()
+        ));
+        // the log is only rejected if enough providers agree that it does not exist
+        if responses.len() - count < min_agreeing {
+            undecided = true;
+        }
+    }
+    accepted.sort_by_key(|log| (log.block_number, log.log_index));
+    LogsConsensus {
+        accepted,
+        disagreements,
+        undecided,
+    }
+}
+
+/// Records the balance of the canister's EVM address, which pays the gas of the
+/// callback transactions.
+async fn update_eth_balance<P, T>(provider: &P)
+where
+    P: Provider<T>,
+    T: Transport + Clone,
+{
+    let Some(evm_address) = read_state(|s| s.canister_evm_address) else {
+        return;
+    };
+    match observe_rpc("eth_getBalance", provider.get_balance(evm_address)).await {
+        Ok(balance) => mutate_state(|s| {
+            s.metrics.eth_balance_wei = Some(u128::try_from(balance).unwrap_or(u128::MAX))
+        }),
+        Err(e) => report_error(
+            TaskType::ScrapeLogs,
+            None,
+            format!("Failed to get the balance of {}: {}", evm_address, e),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::log;
+
+    fn log_indexes(logs: &[Log]) -> Vec<u64> {
+        logs.iter().filter_map(|log| log.log_index).collect()
+    }
+
+    #[test]
+    fn should_accept_logs_returned_by_enough_providers() {
+        let responses = vec![
+            vec![log(2), log(0), log(1)],
+            vec![log(0), log(2)],
+            vec![log(2), log(0), log(1)],
+        ];
+        let consensus = logs_consensus(&responses, 2);
+        assert_eq!(log_indexes(&consensus.accepted), vec![0, 1, 2]);
+        assert!(consensus.disagreements.is_empty());
+        assert!(!consensus.undecided);
+    }
+
+    #[test]
+    fn should_reject_logs_that_enough_providers_did_not_return() {
+        let responses = vec![vec![log(0), log(1)], vec![log(0)], vec![log(0)]];
+        let consensus = logs_consensus(&responses, 2);
+        assert_eq!(log_indexes(&consensus.accepted), vec![0]);
+        assert_eq!(consensus.disagreements.len(), 1);
+        assert!(!consensus.undecided);
+    }
+
+    #[test]
+    fn should_be_undecided_without_quorum_for_or_against_a_log() {
+        let responses = vec![vec![log(0), log(1)], vec![log(0)]];
+        let consensus = logs_consensus(&responses, 2);
+        assert_eq!(log_indexes(&consensus.accepted), vec![0]);
+        assert_eq!(consensus.disagreements.len(), 1);
+        assert!(consensus.undecided);
+    }
+
+    #[test]
+    fn should_count_repeated_log_once_per_response() {
+        let responses = vec![vec![log(0), log(0)], vec![]];
+        let consensus = logs_consensus(&responses, 2);
+        assert!(consensus.accepted.is_empty());
+        assert!(consensus.undecided);
+    }
+
+    #[test]
+    fn should_distinguish_logs_from_different_blocks() {
+        let reorged = Log {
+            block_hash: Some(B256::repeat_byte(3)),
+            ..log(0)
+        };
+        let responses = vec![vec![log(0)], vec![reorged], vec![log(0)]];
+        let consensus = logs_consensus(&responses, 2);
+        assert_eq!(consensus.accepted, vec![log(0)]);
+        assert_eq!(consensus.disagreements.len(), 1);
+        assert!(!consensus.undecided);
+    }
+
+    #[test]
+    fn should_agree_on_value_of_enough_providers() {
+        assert_eq!(agreed_value(vec![1, 2, 1], 2), Some(1));
+        assert_eq!(agreed_value(vec![1, 2, 3], 2), None);
+        assert_eq!(agreed_value(vec![3], 1), Some(3));
+        assert_eq!(agreed_value(vec![3], 0), Some(3));
+        assert_eq!(agreed_value(Vec::<u64>::new(), 0), None);
+    }
+
+    #[test]
+    fn should_agree_on_highest_head_reached_by_enough_providers() {
+        assert_eq!(agreed_head_block_number(vec![100, 105, 102], 2), Some(102));
+        assert_eq!(agreed_head_block_number(vec![100, 105, 102], 3), Some(100));
+        assert_eq!(agreed_head_block_number(vec![100, 105, 102], 1), Some(105));
+        assert_eq!(agreed_head_block_number(vec![100], 2), None);
+        assert_eq!(agreed_head_block_number(vec![], 0), None);
+    }
+
+    #[test]
+    fn should_detect_range_too_large_errors() {
+        for error in [
+            "query returned more than 10000 results",
+            "block range is too large",
+            "eth_getLogs block range is too wide, max 2000 blocks",
+            "Log response size exceeded. You can make eth_getLogs requests with up to a \
+             2K block range",
+            "Http body exceeds size limit of 100000 bytes.",
+        ] {
+            assert!(is_range_too_large(error), "{error}");
+        }
+    }
+
+    #[test]
+    fn should_not_treat_quota_errors_as_range_too_large() {
+        for error in [
+            "Your app has exceeded its compute units per second capacity",
+            "rate limit exceeded",
+            "daily request count exceeded, request rate limited",
+            "more than 5 requests per second",
+            "connection refused",
+        ] {
+            assert!(!is_range_too_large(error), "{error}");
+        }
+    }
+
+    #[test]
+    fn should_ignore_fields_providers_fill_in_differently() {
+        let responses = vec![
+            vec![Log {
+                block_timestamp: Some(1_700_000_000),
+                ..log(0)
+            }],
+            vec![Log {
+                transaction_index: Some(7),
+                ..log(0)
+            }],
+        ];
+        let consensus = logs_consensus(&responses, 2);
+        assert_eq!(log_indexes(&consensus.accepted), vec![0]);
+        assert!(consensus.disagreements.is_empty());
+        assert!(!consensus.undecided);
+    }
+
+    #[test]
+    fn should_not_agree_on_logs_with_different_data() {
+        let mut forged = log(0);
+        forged.inner.data = LogData::new_unchecked(vec![B256::repeat_byte(4)], Default::default());
+        let responses = vec![vec![log(0)], vec![forged]];
+        let consensus = logs_consensus(&responses, 2);
+        assert!(consensus.accepted.is_empty());
+        assert_eq!(consensus.disagreements.len(), 2);
+        assert!(consensus.undecided);
+    }
 }
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::IntoFuture;
+
+use ic_canisters_http_types::{HttpResponse, HttpResponseBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::state::{mutate_state, read_state, Receipt, State};
+
+/// Counters exposed in the Prometheus format on `/metrics`.
+///
+/// The counters are kept across upgrades. Cycles are measured as the change of the
+/// canister balance during a call, so calls made concurrently by other tasks can be
+/// attributed to the wrong call.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metrics {
+    pub jobs_received: u64,
+    pub jobs_succeeded: u64,
+    pub jobs_failed: u64,
+    pub jobs_retried: u64,
+    /// The number of RPC calls by method.
+    pub rpc_calls: BTreeMap<String, u64>,
+    /// The number of failed RPC calls by method.
+    pub rpc_errors: BTreeMap<String, u64>,
+    pub outcall_cycles: u128,
+    pub signatures: u64,
+    pub signature_cycles: u128,
+    /// The gas used by all mined callback transactions.
+    pub gas_used: u128,
+    /// The fees paid for all mined callback transactions, in wei.
+    pub gas_fees_wei: u128,
+    /// The balance of the canister's EVM address, in wei.
+    pub eth_balance_wei: Option<u128>,
+    /// The most recent block whose logs may be scraped according to the finality mode.
+    pub head_block_number: Option<u64>,
+}
+
+impl Metrics {
+    pub fn record_rpc_call(&mut self, method: &str, succeeded: bool, cycles: u128) {
+        *self.rpc_calls.entry(method.to_string()).or_default() += 1;
+        if !succeeded {
+            *self.rpc_errors.entry(method.to_string()).or_default() += 1;
+        }
+        self.outcall_cycles = self.outcall_cycles.saturating_add(cycles);
+    }
+
+    pub fn record_signature(&mut self, cycles: u128) {
+        self.signatures += 1;
+        self.signature_cycles = self.signature_cycles.saturating_add(cycles);
+    }
+
+    /// Records the gas of a mined transaction. A batch transaction is recorded once.
+    pub fn record_gas(&mut self, receipt: &Receipt) {
+        self.gas_used = self.gas_used.saturating_add(receipt.gas_used as u128);
+        self.gas_fees_wei = self
+            .gas_fees_wei
+            .saturating_add(receipt.gas_used as u128 * receipt.effective_gas_price as u128);
+    }
+}
+
+/// Runs an RPC call and records it, together with the cycles it cost, under `method`.
+pub async fn observe_rpc<T, E>(
+    method: &'static str,
+    call: impl IntoFuture<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let balance_before = ic_cdk::api::canister_balance128();
+    let result = call.await;
+    let cycles = balance_before.saturating_sub(ic_cdk::api::canister_balance128());
+    mutate_state(|s| s.metrics.record_rpc_call(method, result.is_ok(), cycles));
+    result
+}
+
+/// Runs a threshold ECDSA signing call and records the cycles it cost.
+pub async fn observe_signature<T>(call: impl IntoFuture<Output = T>) -> T {
+    let balance_before = ic_cdk::api::canister_balance128();
+    let result = call.await;
+    let cycles = balance_before.saturating_sub(ic_cdk::api::canister_balance128());
+    mutate_state(|s| s.metrics.record_signature(cycles));
+    result
+}
+
+/// Writes metrics in the Prometheus text exposition format.
+struct MetricsEncoder {
+    buf: String,
+}
+
+impl MetricsEncoder {
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.buf, "# HELP {name} {help}").unwrap();
+        writeln!(self.buf, "# TYPE {name} {kind}").unwrap();
+    }
+
+    fn counter(&mut self, name: &str, value: f64, help: &str) {
+        self.header(name, "counter", help);
+        writeln!(self.buf, "{name} {}", value).unwrap();
+    }
+
+    fn gauge(&mut self, name: &str, value: f64, help: &str) {
+        self.header(name, "gauge", help);
+        writeln!(self.buf, "{name} {}", value).unwrap();
+    }
+
+    fn counter_by_method(&mut self, name: &str, values: &BTreeMap<String, u64>, help: &str) {
+        self.header(name, "counter", help);
+        for (method, value) in values {
+            writeln!(self.buf, "{name}{{method=\"{method}\"}} {value}").unwrap();
+        }
+    }
+}
+
+fn encode_metrics(state: &State) -> String {
+    let metrics = &state.metrics;
+    let mut encoder = MetricsEncoder { buf: String::new() };
+    encoder.counter(
+        "chain_fusion_jobs_received_total",
+        metrics.jobs_received as f64,
+        "The number of jobs received from scraped logs.",
+    );
+    encoder.counter(
+        "chain_fusion_jobs_succeeded_total",
+        metrics.jobs_succeeded as f64,
+        "The number of jobs whose callback transaction was mined successfully.",
+    );
+    encoder.counter(
+        "chain_fusion_jobs_failed_total",
+        metrics.jobs_failed as f64,
+        "The number of jobs moved to the dead letter list.",
+    );
+    encoder.counter(
+        "chain_fusion_jobs_retried_total",
+        metrics.jobs_retried as f64,
+        "The number of failed job attempts that were scheduled for a retry.",
+    );
+    encoder.counter_by_method(
+        "chain_fusion_rpc_calls_total",
+        &metrics.rpc_calls,
+        "The number of calls to the EVM RPC canister by method.",
+    );
+    encoder.counter_by_method(
+        "chain_fusion_rpc_errors_total",
+        &metrics.rpc_errors,
+        "The number of failed calls to the EVM RPC canister by method.",
+    );
+    encoder.counter(
+        "chain_fusion_outcall_cycles_total",
+        metrics.outcall_cycles as f64,
+        "The cycles spent on calls to the EVM RPC canister.",
+    );
+    encoder.counter(
+        "chain_fusion_signatures_total",
+        metrics.signatures as f64,
+        "The number of threshold ECDSA signatures.",
+    );
+    encoder.counter(
+        "chain_fusion_signature_cycles_total",
+        metrics.signature_cycles as f64,
+        "The cycles spent on threshold ECDSA signatures.",
+    );
+    encoder.counter(
+        "chain_fusion_gas_used_total",
+        metrics.gas_used as f64,
+        "The gas used by mined callback transactions.",
+    );
+    encoder.counter(
+        "chain_fusion_gas_fees_wei_total",
+        metrics.gas_fees_wei as f64,
+        "The fees paid for mined callback transactions, in wei.",
+    );
+    if let Some(eth_balance_wei) = metrics.eth_balance_wei {
+        encoder.gauge(
+            "chain_fusion_eth_balance_wei",
+            eth_balance_wei as f64,
+            "The balance of the canister's EVM address, in wei.",
+        );
+    }
+    if let (Some(head), Some(last_scraped)) =
+        (metrics.head_block_number, state.last_scraped_block_number)
+    {
+        encoder.gauge(
+            "chain_fusion_scrape_lag_blocks",
+            head.saturating_sub(last_scraped) as f64,
+            "The number of blocks up to the head block whose logs were not scraped yet.",
+        );
+    }
+    encoder.gauge(
+        "chain_fusion_cycle_balance",
+        ic_cdk::api::canister_balance128() as f64,
+        "The cycle balance of the canister.",
+    );
+    encoder.buf
+}
+
+/// Serves the metrics on `/metrics`.
+pub fn serve_metrics() -> HttpResponse {
+    let body = read_state(encode_metrics);
+    HttpResponseBuilder::ok()
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .with_body_and_content_length(body.into_bytes())
+        .build()
+}
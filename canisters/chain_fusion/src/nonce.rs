@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use alloy::primitives::{Address, FixedBytes};
+use alloy::providers::{Provider, ProviderBuilder};
+use alloy::transports::icp::IcpConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::job::JobError;
+use crate::metrics::observe_rpc;
+use crate::state::{mutate_state, read_state};
+
+/// Hands out the nonces for the transactions sent from the canister's EVM address.
+///
+/// Nonces are reserved synchronously, so concurrent jobs never get the same nonce.
+/// A reserved nonce is either marked as in flight once its transaction was sent, or
+/// released again. Released nonces and the nonces of dropped transactions are
+/// handed out again before new ones, so that no gap blocks later transactions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NonceManager {
+    /// The lowest nonce that was never handed out, `None` until synced with the chain.
+    next_nonce: Option<u64>,
+    /// Nonces below `next_nonce` that are not used by any transaction.
+    gaps: BTreeSet<u64>,
+    /// Nonces reserved by jobs that did not send their transaction yet.
+    reserved: BTreeSet<u64>,
+    /// The hashes of sent transactions that were not confirmed yet, by nonce.
+    in_flight: BTreeMap<u64, FixedBytes<32>>,
+}
+
+impl NonceManager {
+    pub fn is_synced(&self) -> bool {
+        self.next_nonce.is_some()
+    }
+
+    /// Returns the lowest nonce that was never handed out, `None` until synced with
+    /// the chain.
+    pub fn next_nonce(&self) -> Option<u64> {
+        self.next_nonce
+    }
+
+    fn is_used(&self, nonce: u64) -> bool {
+        self.reserved.contains(&nonce) || self.in_flight.contains_key(&nonce)
+    }
+
+    /// Reserves the lowest available nonce. Returns `None` if the manager was not
+    /// synced with the chain yet.
+    pub fn reserve(&mut self) -> Option<u64> {
+        let next_nonce = self.next_nonce?;
+        let nonce = match self.gaps.pop_first() {
+            Some(nonce) => nonce,
+            None => {
+                let mut nonce = next_nonce;
+                while self.is_used(nonce) {
+                    nonce += 1;
+                }
+                self.next_nonce = Some(nonce + 1);
+                nonce
+            }
+        };
+        self.reserved.insert(nonce);
+        Some(nonce)
+    }
+
+    /// Records that the transaction with a reserved nonce was sent, or that a
+    /// transaction in flight was replaced.
+    pub fn record_sent(&mut self, nonce: u64, transaction_hash: FixedBytes<32>) {
+        self.reserved.remove(&nonce);
+        self.in_flight.insert(nonce, transaction_hash);
+    }
+
+    /// Releases a reserved nonce whose transaction could not be sent.
+    pub fn release(&mut self, nonce: u64) {
+        if self.reserved.remove(&nonce) {
+            self.record_gap(nonce);
+        }
+    }
+
+    /// Releases the nonce of a sent transaction that is no longer known to the node.
+    pub fn record_dropped(&mut self, nonce: u64, transaction_hash: FixedBytes<32>) {
+        if self.in_flight.get(&nonce) == Some(&transaction_hash) {
+            self.in_flight.remove(&nonce);
+            self.record_gap(nonce);
+        }
+    }
+
+    /// Records that a transaction with the given nonce was mined.
+    pub fn record_confirmed(&mut self, nonce: u64) {
+        self.in_flight.remove(&nonce);
+    }
+
+    fn record_gap(&mut self, nonce: u64) {
+        if self.next_nonce.is_some_and(|next_nonce| nonce < next_nonce) {
+            self.gaps.insert(nonce);
+        }
+    }
+
+    /// Resets the manager to the pending transaction count of the canister's address.
+    ///
+    /// All nonces below the count are used on chain or in the mempool, so there are
+    /// no gaps left. In-flight transactions with a higher nonce were dropped by the node.
+    pub fn sync(&mut self, pending_transaction_count: u64) {
+        self.next_nonce = Some(pending_transaction_count);
+        self.gaps.clear();
+        self.in_flight
+            .retain(|nonce, _| *nonce < pending_transaction_count);
+    }
+
+    /// Forgets the reserved nonces of jobs that were interrupted, e.g. by an upgrade.
+    /// Their transactions may or may not have been sent, so the manager is synced with
+    /// the chain again before the next reservation.
+    pub fn reset_reserved(&mut self) {
+        if !self.reserved.is_empty() {
+            self.reserved.clear();
+            self.gaps.clear();
+            self.next_nonce = None;
+        }
+    }
+}
+
+/// Returns `true` if a transaction was rejected because its nonce does not match
+/// the account's nonce on chain.
+pub fn is_nonce_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("nonce too low") || message.contains("nonce too high")
+}
+
+/// Syncs the nonce manager with the pending transaction count of `address`.
+pub async fn resync_nonce(address: Address) -> Result<(), JobError> {
+    let rpc_service = read_state(|s| s.rpc_service.clone());
+    let provider = ProviderBuilder::new().on_icp(IcpConfig::new(rpc_service));
+    let pending_transaction_count = observe_rpc(
+        "eth_getTransactionCount",
+        provider.get_transaction_count(address).pending(),
+    )
+    .await
+    .map_err(|e| JobError::Rpc(e.to_string()))?;
+    mutate_state(|s| s.nonces.sync(pending_transaction_count));
+    Ok(())
+}
+
+/// Reserves a nonce for a transaction sent from `address`, syncing the nonce
+/// manager with the chain first if necessary.
+pub async fn reserve_nonce(address: Address) -> Result<u64, JobError> {
+    if !read_state(|s| s.nonces.is_synced()) {
+        resync_nonce(address).await?;
+    }
+    Ok(mutate_state(|s| s.nonces.reserve()).expect("BUG: nonce manager is synced"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn synced(pending_transaction_count: u64) -> NonceManager {
+        let mut nonces = NonceManager::default();
+        nonces.sync(pending_transaction_count);
+        nonces
+    }
+
+    #[test]
+    fn should_not_reserve_before_sync() {
+        let mut nonces = NonceManager::default();
+        assert!(!nonces.is_synced());
+        assert_eq!(nonces.reserve(), None);
+    }
+
+    #[test]
+    fn should_reserve_consecutive_nonces() {
+        let mut nonces = synced(5);
+        assert_eq!(nonces.reserve(), Some(5));
+        assert_eq!(nonces.reserve(), Some(6));
+        nonces.record_sent(5, FixedBytes::repeat_byte(5));
+        assert_eq!(nonces.reserve(), Some(7));
+        assert_eq!(nonces.next_nonce(), Some(8));
+    }
+
+    #[test]
+    fn should_reuse_released_nonces() {
+        let mut nonces = synced(0);
+        assert_eq!(nonces.reserve(), Some(0));
+        assert_eq!(nonces.reserve(), Some(1));
+        assert_eq!(nonces.reserve(), Some(2));
+        nonces.release(1);
+        nonces.release(0);
+        nonces.release(2);
+        // releasing a nonce twice does not hand it out twice
+        nonces.release(2);
+        assert_eq!(nonces.reserve(), Some(0));
+        assert_eq!(nonces.reserve(), Some(1));
+        assert_eq!(nonces.reserve(), Some(2));
+        assert_eq!(nonces.reserve(), Some(3));
+    }
+
+    #[test]
+    fn should_reuse_nonces_of_dropped_transactions() {
+        let mut nonces = synced(0);
+        assert_eq!(nonces.reserve(), Some(0));
+        assert_eq!(nonces.reserve(), Some(1));
+        nonces.record_sent(0, FixedBytes::repeat_byte(1));
+        nonces.record_sent(1, FixedBytes::repeat_byte(2));
+        // the transaction was replaced, so the old hash is ignored
+        nonces.record_sent(0, FixedBytes::repeat_byte(3));
+        nonces.record_dropped(0, FixedBytes::repeat_byte(1));
+        assert_eq!(nonces.reserve(), Some(2));
+
+        nonces.record_dropped(0, FixedBytes::repeat_byte(3));
+        assert_eq!(nonces.reserve(), Some(0));
+
+        nonces.record_confirmed(1);
+        nonces.record_dropped(1, FixedBytes::repeat_byte(2));
+        assert_eq!(nonces.reserve(), Some(3));
+    }
+
+    #[test]
+    fn should_skip_used_nonces_after_sync() {
+        let mut nonces = synced(0);
+        for nonce in 0..4 {
+            assert_eq!(nonces.reserve(), Some(nonce));
+        }
+        nonces.record_sent(1, FixedBytes::repeat_byte(1));
+        nonces.record_sent(3, FixedBytes::repeat_byte(3));
+        nonces.release(0);
+
+        // nonce 1 is pending, nonce 3 was dropped by the node and 2 is still reserved
+        nonces.sync(2);
+        assert_eq!(nonces.next_nonce(), Some(2));
+        assert_eq!(nonces.reserve(), Some(3));
+        assert_eq!(nonces.reserve(), Some(4));
+
+        // the dropped transaction is no longer tracked, so the reserved nonce stays used
+        nonces.record_dropped(3, FixedBytes::repeat_byte(3));
+        nonces.release(4);
+        assert_eq!(nonces.reserve(), Some(4));
+    }
+
+    #[test]
+    fn should_detect_nonce_errors() {
+        assert!(is_nonce_error("Nonce too low"));
+        assert!(is_nonce_error(
+            "nonce too high: address 0x1, tx: 5 state: 3"
+        ));
+        assert!(!is_nonce_error("insufficient funds for gas"));
+    }
+
+    #[test]
+    fn should_resync_after_reset_of_reserved_nonces() {
+        let mut nonces = synced(0);
+        assert_eq!(nonces.reserve(), Some(0));
+        assert_eq!(nonces.reserve(), Some(1));
+        nonces.record_sent(0, FixedBytes::repeat_byte(1));
+
+        nonces.reset_reserved();
+        assert!(!nonces.is_synced());
+        assert_eq!(nonces.reserve(), None);
+
+        // the transaction with nonce 1 was sent before the interruption
+        nonces.sync(2);
+        assert_eq!(nonces.reserve(), Some(2));
+    }
+
+    #[test]
+    fn should_stay_synced_without_reserved_nonces() {
+        let mut nonces = synced(3);
+        nonces.reset_reserved();
+        assert_eq!(nonces.reserve(), Some(3));
+    }
+}
@@ -1,77 +1,1032 @@
-use alloy::primitives::{Address, FixedBytes};
-use alloy::rpc::types::Log;
+use alloy::primitives::{keccak256, Address, Bytes, FixedBytes, B256, U256};
+use alloy::rpc::types::{Filter, Log};
 use alloy::signers::icp::IcpSigner;
+use alloy::sol_types::SolCall;
 use alloy::transports::icp::RpcService;
 
+use candid::{CandidType, Principal};
 use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
-use std::collections::{BTreeMap, HashSet};
+use ic_cdk_timers::TimerId;
+use minicbor_derive::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashSet, VecDeque};
 
 use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::job::{Callback, JobError};
+use crate::metrics::Metrics;
+use crate::nonce::NonceManager;
+use crate::storage::{self, ProcessedLog};
+use crate::{
+    Coprocessor, DEFAULT_BLOCK_SPREAD, DEFAULT_LOGS_RESPONSE_SIZE, DEFAULT_MAX_CONCURRENT_JOBS,
+    DEFAULT_SCRAPING_LOGS_INTERVAL, DEFAULT_STUCK_TRANSACTION_TIMEOUT, JOB_RETRY_BASE_DELAY,
+    JOB_RETRY_MAX_DELAY, MAX_BLOCK_SPREAD, MAX_CONFIG_CHANGES, MAX_INCONSISTENT_RESPONSES,
+    MAX_JOB_ATTEMPTS, MAX_LOGS_RESPONSE_SIZE,
+};
 
 thread_local! {
     static STATE: RefCell<Option<State>> = RefCell::default();
 }
 
-#[derive(Debug, Clone)]
+/// The canister state.
+///
+/// It is serialized to stable memory in `pre_upgrade` and restored in `post_upgrade`.
+/// Fields marked with `#[serde(skip)]` are transient and are rebuilt after an upgrade.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct State {
     pub rpc_service: RpcService,
+    /// The providers logs are scraped from. Logs are scraped from `rpc_service` if
+    /// empty.
+    #[serde(default)]
+    pub rpc_services: Vec<RpcService>,
+    /// Determines how many of the log providers have to return a log before it is
+    /// accepted.
+    #[serde(default)]
+    pub consensus: ConsensusStrategy,
+    /// The most recent disagreements between the log providers, the latest last.
+    #[serde(default)]
+    pub inconsistent_responses: VecDeque<InconsistentResponse>,
     pub chain_id: u64,
     pub coprocessor_evm_address: Address,
     pub filter_addresses: Vec<Address>,
     pub filter_events: Vec<String>,
-    pub logs_to_process: BTreeMap<LogSource, Log>,
-    pub processed_logs: BTreeMap<LogSource, Log>,
+    /// Filters on the indexed arguments of the scraped events.
+    #[serde(default)]
+    pub filter_topics: TopicFilter,
+    pub logs_to_process: BTreeMap<LogSource, Job>,
+    /// Jobs that ran out of attempts. They are only retried when requeued by a controller.
+    #[serde(default)]
+    pub dead_letter_jobs: BTreeMap<LogSource, Job>,
+    /// Processed logs older than this many blocks are pruned from stable memory.
+    /// Processed logs are kept forever if `None`.
+    #[serde(default)]
+    pub processed_logs_retention_blocks: Option<u64>,
+    /// Processed logs from blocks before this one may have been pruned, so logs from
+    /// these blocks are no longer scraped or rescanned.
+    #[serde(default)]
+    pub processed_logs_pruned_below: u64,
+    #[serde(skip)]
     pub active_tasks: HashSet<TaskType>,
+    /// The jobs that are currently computed.
+    #[serde(skip)]
+    pub active_jobs: BTreeSet<LogSource>,
+    /// The maximum number of jobs computed at the same time.
+    #[serde(default = "default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: u32,
+    /// Computed callbacks waiting to be sent, in the order their nonces are reserved.
+    #[serde(default)]
+    pub submission_queue: VecDeque<QueuedCallback>,
+    /// Send the results of several jobs in one transaction. Every result is sent in
+    /// its own transaction if `None`.
+    #[serde(default)]
+    pub batch: Option<BatchConfig>,
+    #[serde(skip)]
     pub signer: Option<IcpSigner>,
     pub ecdsa_key_id: EcdsaKeyId,
     pub canister_evm_address: Option<Address>,
-    pub nonce: Option<u64>,
+    #[serde(default)]
+    pub nonces: NonceManager,
+    /// The block to start scraping from if no block has been scraped yet.
+    #[serde(default)]
+    pub start_block: Option<u64>,
+    /// The last block whose logs have been recorded.
+    #[serde(default)]
+    pub last_scraped_block_number: Option<u64>,
+    /// The number of blocks queried in a single `eth_getLogs` call, adapted to the
+    /// limits of the providers.
+    #[serde(default = "default_block_spread")]
+    pub block_spread: u64,
+    /// The response size limit of `eth_getLogs` calls, raised if the logs of a single
+    /// block do not fit.
+    #[serde(default = "default_logs_response_size")]
+    pub logs_response_size: u64,
+    /// Block ranges that controllers asked to scrape again, the next first.
+    #[serde(default)]
+    pub rescans: VecDeque<BlockRange>,
+    /// Determines the most recent block whose logs are scraped.
+    #[serde(default)]
+    pub finality: FinalityMode,
+    /// The signature of the function job results are sent to, e.g.
+    /// `callback(uint256,uint256)`. Its parameters are the result and the job id.
+    #[serde(default = "default_callback_signature")]
+    pub callback_signature: String,
+    /// Callback transactions that are not mined after this many seconds are
+    /// replaced with bumped fees.
+    #[serde(default = "default_stuck_transaction_timeout_secs")]
+    pub stuck_transaction_timeout_secs: u64,
+    /// The most recent configuration changes, the latest last.
+    #[serde(default)]
+    pub config_changes: VecDeque<ConfigChange>,
+    /// Determines how often logs are scraped.
+    #[serde(default)]
+    pub polling_interval: PollingInterval,
+    /// The delay before the last scheduled scrape in the adaptive polling mode.
+    #[serde(skip)]
+    pub polling_delay: Option<Duration>,
+    /// The timer of the next scrape.
+    #[serde(skip)]
+    pub scrape_logs_timer: Option<TimerId>,
+    /// Determines which tasks run, set by controllers e.g. during incidents.
+    #[serde(default)]
+    pub mode: Mode,
+    /// The keccak256 hash of the token that grants access to the canister log on
+    /// `/logs`. The log is only served to controllers via `get_logs` if `None`.
+    #[serde(default)]
+    pub logs_token_hash: Option<B256>,
+    #[serde(default)]
+    pub metrics: Metrics,
+    /// The last error of every task since the canister was installed or upgraded.
+    #[serde(skip)]
+    pub last_errors: BTreeMap<TaskType, TaskError>,
+}
+
+pub fn default_callback_signature() -> String {
+    Coprocessor::callbackCall::SIGNATURE.to_string()
+}
+
+pub fn default_max_concurrent_jobs() -> u32 {
+    DEFAULT_MAX_CONCURRENT_JOBS
+}
+
+pub fn default_stuck_transaction_timeout_secs() -> u64 {
+    DEFAULT_STUCK_TRANSACTION_TIMEOUT.as_secs()
+}
+
+pub fn default_block_spread() -> u64 {
+    DEFAULT_BLOCK_SPREAD
+}
+
+pub fn default_logs_response_size() -> u64 {
+    DEFAULT_LOGS_RESPONSE_SIZE
+}
+
+/// Determines which block is considered the head of the chain when scraping logs.
+/// Logs from blocks after the head are not processed until the head catches up.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FinalityMode {
+    /// The latest block minus the given number of confirmations.
+    Latest { confirmations: u64 },
+    /// The latest block considered safe by the consensus layer.
+    Safe,
+    /// The latest finalized block.
+    Finalized,
+}
+
+impl Default for FinalityMode {
+    fn default() -> Self {
+        Self::Latest { confirmations: 0 }
+    }
+}
+
+/// Determines how many log providers have to agree on a log, like the
+/// `ConsensusStrategy` of the EVM RPC canister.
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConsensusStrategy {
+    /// All providers have to return the same logs.
+    #[default]
+    Equality,
+    /// At least `min` providers have to return a log. `total`, if given, has to match
+    /// the number of providers.
+    Threshold { total: Option<u8>, min: u8 },
+}
+
+/// Filters on the indexed arguments of the scraped events. A log matches if each of
+/// its topics is one of the listed values, a topic without a list matches any value.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TopicFilter {
+    pub topic1: Option<Vec<B256>>,
+    pub topic2: Option<Vec<B256>>,
+    pub topic3: Option<Vec<B256>>,
+}
+
+/// The blocks `from_block..=to_block`.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockRange {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+/// A block range for which the log providers did not agree.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InconsistentResponse {
+    /// When the responses were received, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub from_block: u64,
+    pub to_block: u64,
+    pub description: String,
+}
+
+/// Determines which tasks of the coprocessor run.
+#[derive(CandidType, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Logs are scraped and jobs are processed.
+    #[default]
+    Running,
+    /// No logs are scraped, the jobs of already scraped logs are still processed.
+    ScrapingPaused,
+    /// Logs are scraped, but no jobs are started and no transactions are sent.
+    ProcessingPaused,
+    /// Neither logs are scraped nor jobs processed.
+    Paused,
+    /// No logs are scraped and no new jobs are started, but the jobs in flight are
+    /// finished and their results sent. Jobs in flight include those waiting for a
+    /// retry after a failed attempt or a dropped transaction.
+    Draining,
+}
+
+impl Mode {
+    pub fn pause_scraping(self) -> Self {
+        match self {
+            Mode::Running => Mode::ScrapingPaused,
+            Mode::ProcessingPaused => Mode::Paused,
+            mode => mode,
+        }
+    }
+
+    pub fn pause_processing(self) -> Self {
+        match self {
+            Mode::Running => Mode::ProcessingPaused,
+            Mode::ScrapingPaused | Mode::Draining => Mode::Paused,
+            mode => mode,
+        }
+    }
+
+    /// Returns `true` if the task must not run in this mode.
+    pub fn pauses(self, task: TaskType) -> bool {
+        match task {
+            TaskType::ScrapeLogs => self != Mode::Running && self != Mode::ProcessingPaused,
+            TaskType::ProcessLogs => matches!(self, Mode::ProcessingPaused | Mode::Paused),
+            TaskType::SubmitResults => matches!(self, Mode::ProcessingPaused | Mode::Paused),
+            TaskType::TrackTransactions => false,
+        }
+    }
+}
+
+/// Determines how long the log poller waits after a scrape before the next one.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PollingInterval {
+    /// Waits the same number of seconds after every scrape.
+    Fixed { secs: u64 },
+    /// Waits `min_secs` after a scrape that found new logs and doubles the wait
+    /// after every scrape without new logs, up to `max_secs`.
+    Adaptive { min_secs: u64, max_secs: u64 },
+}
+
+impl Default for PollingInterval {
+    fn default() -> Self {
+        PollingInterval::Fixed {
+            secs: DEFAULT_SCRAPING_LOGS_INTERVAL.as_secs(),
+        }
+    }
+}
+
+/// Determines how many job results are sent together in one transaction to
+/// `callbackBatch(uint256[],bytes[])` of the coprocessor contract.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// The maximum number of results sent in one transaction.
+    pub max_size: u32,
+    /// How long results are collected before a batch is sent, in seconds.
+    pub window_secs: u64,
+}
+
+/// An entry of the configuration audit trail.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfigChange {
+    /// When the change was made, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub change: String,
+}
+
+/// The last error of a task.
+#[derive(CandidType, Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TaskError {
+    pub task: TaskType,
+    /// When the error occurred, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub message: String,
+}
+
+/// A computed callback waiting to be sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueuedCallback {
+    pub log_source: LogSource,
+    pub callback: Callback,
+    /// When the callback was queued, in nanoseconds since the epoch.
+    pub queued_at: u64,
+}
+
+/// The lifecycle of the job triggered by a log.
+#[derive(CandidType, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub enum JobStatus {
+    /// The job waits to be run.
+    #[n(0)]
+    Pending,
+    /// The job result is being computed or waits to be sent.
+    #[n(1)]
+    Submitting,
+    /// The transaction with the job result was sent but not mined yet.
+    #[n(2)]
+    Submitted,
+    /// The transaction with the job result was mined successfully.
+    #[n(3)]
+    Confirmed,
+    /// The job ran out of attempts.
+    #[n(4)]
+    Failed,
+    /// The log's block is no longer part of the canonical chain.
+    #[n(5)]
+    Reorged,
+}
+
+/// A job that has not been confirmed yet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub log: Log,
+    pub status: JobStatus,
+    pub attempts: u32,
+    /// The hash of the latest transaction that sent the job result.
+    pub transaction_hash: Option<FixedBytes<32>>,
+    #[serde(default)]
+    pub submission: Option<Submission>,
+    /// The receipt of a reverted transaction.
+    #[serde(default)]
+    pub receipt: Option<Receipt>,
+    /// The id of the job as returned by its handler.
+    pub job_id: Option<U256>,
+    /// The ABI-encoded result computed by the handler.
+    #[serde(default)]
+    pub result: Option<Bytes>,
+    /// The job is not retried before this time, in nanoseconds since the epoch.
+    pub next_attempt_at: u64,
+    /// The error of the last failed attempt.
+    pub last_error: Option<JobError>,
+}
+
+impl Job {
+    pub fn new(log: Log) -> Self {
+        Self {
+            log,
+            status: JobStatus::Pending,
+            attempts: 0,
+            transaction_hash: None,
+            submission: None,
+            receipt: None,
+            job_id: None,
+            result: None,
+            next_attempt_at: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// The callback transaction of a job, kept to replace it if it gets stuck.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Submission {
+    pub to: Address,
+    pub input: Bytes,
+    pub nonce: u64,
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    /// The hashes of all transactions sent with this nonce, the latest last.
+    pub transaction_hashes: Vec<FixedBytes<32>>,
+    /// When the latest transaction was sent, in nanoseconds since the epoch.
+    pub sent_at: u64,
+}
+
+impl Submission {
+    pub fn latest_transaction_hash(&self) -> FixedBytes<32> {
+        *self
+            .transaction_hashes
+            .last()
+            .expect("BUG: submission without transaction")
+    }
+}
+
+/// The outcome of a mined callback transaction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Encode, Decode)]
+pub struct Receipt {
+    #[n(0)]
+    pub succeeded: bool,
+    #[n(1)]
+    pub gas_used: u64,
+    /// The price paid per unit of gas, in wei.
+    #[n(2)]
+    pub effective_gas_price: u64,
+}
+
+/// Returns the exponential backoff after the given number of failed attempts.
+fn retry_delay(attempts: u32) -> Duration {
+    JOB_RETRY_BASE_DELAY
+        .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(JOB_RETRY_MAX_DELAY)
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum InvalidStateError {
     InvalidEthereumContractAddress(String),
+    InvalidEventSignature(String),
+    InvalidCallbackSignature(String),
+    InvalidConsensusStrategy(String),
+    InvalidTopicFilter(String),
+    InvalidPollingInterval(String),
 }
 
 impl State {
+    /// Records a scraped log so that its job is run by `process_logs`.
+    ///
+    /// Logs that are already pending in the same block, were already processed or
+    /// whose job is in the dead letter list are ignored. Logs with `removed` set drop the pending log with the same source
+    /// if its job was not started yet. A started job holds a nonce and may have sent
+    /// its result already, so it is left to finish.
     pub fn record_log_to_process(&mut self, log_entry: &Log) {
         let event_source = log_entry.source();
-        assert!(
-            !self.logs_to_process.contains_key(&event_source),
-            "there must be no two different events with the same source"
+        if log_entry.removed {
+            let is_pending = self
+                .logs_to_process
+                .get(&event_source)
+                .is_some_and(|job| job.status == JobStatus::Pending);
+            if is_pending {
+                self.record_reorged_log(&event_source);
+            }
+            return;
+        }
+        // without its processed log, a log from a pruned block cannot be told apart
+        // from a new one
+        if log_entry.block_number.unwrap_or_default() < self.processed_logs_pruned_below {
+            return;
+        }
+        if storage::get_processed_log(&event_source)
+            .is_some_and(|processed_log| processed_log.status != JobStatus::Reorged)
+        {
+            return;
+        }
+        // a dead letter job only runs again when a controller requeues it
+        if self.dead_letter_jobs.contains_key(&event_source) {
+            return;
+        }
+        if let Some(pending_job) = self.logs_to_process.get(&event_source) {
+            if pending_job.log.block_hash == log_entry.block_hash
+                || pending_job.status != JobStatus::Pending
+            {
+                return;
+            }
+            // The log was re-delivered in a different block after a reorg,
+            // the newer log replaces the pending one.
+        }
+
+        self.logs_to_process
+            .insert(event_source, Job::new(log_entry.clone()));
+        self.metrics.jobs_received += 1;
+    }
+
+    /// Drops a pending log whose block is no longer part of the canonical chain and
+    /// records it as [`JobStatus::Reorged`].
+    ///
+    /// The scraping cursor is moved back before the log's block, so that the log is
+    /// picked up again if its transaction was included in the new chain.
+    ///
+    /// Returns `false` and keeps the job if it is unknown or no longer
+    /// [`JobStatus::Pending`], because a running job holds a nonce and is updated when
+    /// its attempt completes.
+    pub fn record_reorged_log(&mut self, source: &LogSource) -> bool {
+        let is_pending = self
+            .logs_to_process
+            .get(source)
+            .is_some_and(|job| job.status == JobStatus::Pending);
+        if !is_pending || self.active_jobs.contains(source) {
+            return false;
+        }
+        let job = self
+            .logs_to_process
+            .remove(source)
+            .expect("BUG: pending job not found");
+        let block_number = job.log.block_number.unwrap_or_default();
+
+        storage::record_processed_log(
+            source.clone(),
+            ProcessedLog {
+                block_number,
+                job_id: None,
+                status: JobStatus::Reorged,
+                transaction_hash: None,
+                receipt: None,
+                attempts: job.attempts,
+                result: None,
+            },
         );
-        assert!(!self.processed_logs.contains_key(&event_source));
+        if self
+            .last_scraped_block_number
+            .is_some_and(|last_scraped| last_scraped >= block_number)
+        {
+            self.last_scraped_block_number = block_number.checked_sub(1);
+        }
+        true
+    }
+
+    /// Returns the transactions that were sent but not mined yet, together with the
+    /// jobs whose result they carry. A batch transaction carries several results.
+    pub fn submitted_transactions(&self) -> Vec<(Submission, Vec<(LogSource, Job)>)> {
+        let mut transactions: BTreeMap<u64, (Submission, Vec<(LogSource, Job)>)> = BTreeMap::new();
+        for (source, job) in &self.logs_to_process {
+            let Some(submission) = job.submission.as_ref() else {
+                continue;
+            };
+            if job.status == JobStatus::Submitted {
+                transactions
+                    .entry(submission.nonce)
+                    .or_insert_with(|| (submission.clone(), vec![]))
+                    .1
+                    .push((source.clone(), job.clone()));
+            }
+        }
+        transactions.into_values().collect()
+    }
+
+    /// Returns the number of jobs that were started but are not confirmed yet,
+    /// including pending jobs that wait for a retry.
+    pub fn in_flight_jobs(&self) -> usize {
+        self.logs_to_process
+            .values()
+            .filter(|job| job.status != JobStatus::Pending || job.attempts > 0)
+            .count()
+    }
+
+    /// Returns up to `limit` jobs that were not confirmed yet, including dead letter
+    /// jobs, ordered by log source and starting at `from`, and the source to continue
+    /// from if there are more.
+    pub fn list_pending_jobs(
+        &self,
+        from: Option<&LogSource>,
+        limit: usize,
+    ) -> (Vec<(&LogSource, &Job)>, Option<LogSource>) {
+        fn range_from<'a>(
+            jobs: &'a BTreeMap<LogSource, Job>,
+            from: Option<&LogSource>,
+        ) -> btree_map::Range<'a, LogSource, Job> {
+            match from {
+                Some(from) => jobs.range(from.clone()..),
+                None => jobs.range(..),
+            }
+        }
+        // a job is either pending or dead lettered, so merging both maps keeps the
+        // sources unique and ordered
+        let mut pending = range_from(&self.logs_to_process, from).peekable();
+        let mut dead_letter = range_from(&self.dead_letter_jobs, from).peekable();
+        let mut jobs = std::iter::from_fn(|| match (pending.peek(), dead_letter.peek()) {
+            (Some((source, _)), Some((dead_letter_source, _))) if dead_letter_source < source => {
+                dead_letter.next()
+            }
+            (Some(_), _) => pending.next(),
+            (None, _) => dead_letter.next(),
+        });
+        let page = jobs.by_ref().take(limit).collect();
+        (page, jobs.next().map(|(source, _)| source.clone()))
+    }
+
+    /// Returns the pending jobs that are due at `now` (in nanoseconds since the epoch).
+    /// While draining, only jobs that were started before are retried.
+    pub fn jobs_to_run(&self, now: u64) -> Vec<(LogSource, Job)> {
+        let draining = self.mode == Mode::Draining;
+        self.logs_to_process
+            .iter()
+            .filter(|(_, job)| job.status == JobStatus::Pending && job.next_attempt_at <= now)
+            .filter(|(_, job)| !draining || job.attempts > 0)
+            .map(|(source, job)| (source.clone(), job.clone()))
+            .collect()
+    }
+
+    /// Recovers the jobs that were computed or sent when the canister was upgraded.
+    /// Jobs whose callback is not queued anymore are run again and the nonces they
+    /// reserved are forgotten, so they neither stay `Submitting` nor leave a nonce gap.
+    pub fn recover_interrupted_jobs(&mut self) {
+        let queued: BTreeSet<&LogSource> = self
+            .submission_queue
+            .iter()
+            .map(|queued| &queued.log_source)
+            .collect();
+        for (source, job) in self.logs_to_process.iter_mut() {
+            if job.status == JobStatus::Submitting && !queued.contains(source) {
+                job.status = JobStatus::Pending;
+                job.next_attempt_at = 0;
+            }
+        }
+        self.nonces.reset_reserved();
+    }
+
+    /// Starts a new attempt to compute and send the job result.
+    pub fn record_job_attempt(&mut self, source: &LogSource) {
+        let job = self.pending_job_mut(source);
+        job.attempts += 1;
+        job.status = JobStatus::Submitting;
+    }
 
-        self.logs_to_process.insert(event_source, log_entry.clone());
+    /// Queues the callback computed by a job for submission.
+    pub fn record_job_computed(&mut self, source: &LogSource, callback: Callback, now: u64) {
+        let job = self.pending_job_mut(source);
+        job.job_id = callback.job_id;
+        job.result = callback.result_bytes().cloned();
+        self.submission_queue.push_back(QueuedCallback {
+            log_source: source.clone(),
+            callback,
+            queued_at: now,
+        });
     }
 
-    pub fn record_processed_log(&mut self, source: LogSource) {
-        let log_entry = match self.logs_to_process.remove(&source) {
-            Some(event) => event,
-            None => panic!("attempted to run job for an unknown event {source:?}"),
+    /// Removes the next callbacks to send from the submission queue.
+    ///
+    /// Without batching this is the first queued callback. With batching, batchable
+    /// callbacks are collected until `max_size` callbacks are queued or the oldest
+    /// one waited for `window_secs`. Returns `Err` with the time at which the batch
+    /// is complete if it should not be sent yet.
+    pub fn take_callbacks_to_send(&mut self, now: u64) -> Result<Vec<QueuedCallback>, u64> {
+        let Some(first) = self.submission_queue.front() else {
+            return Ok(vec![]);
+        };
+        let batch = match self.batch {
+            Some(batch) if first.callback.is_batchable() => batch,
+            _ => return Ok(self.submission_queue.pop_front().into_iter().collect()),
         };
+        let max_size = batch.max_size.max(1) as usize;
+        let batchable = self
+            .submission_queue
+            .iter()
+            .take_while(|queued| queued.callback.is_batchable())
+            .count();
+        let window_ends_at = first
+            .queued_at
+            .saturating_add(Duration::from_secs(batch.window_secs).as_nanos() as u64);
+        if batchable < max_size && now < window_ends_at {
+            return Err(window_ends_at);
+        }
+        Ok(self
+            .submission_queue
+            .drain(..batchable.min(max_size))
+            .collect())
+    }
 
-        assert_eq!(
-            self.processed_logs.insert(source.clone(), log_entry),
-            None,
-            "attempted to run job twice for the same event {source:?}"
+    /// Returns the number of jobs that can be started without exceeding
+    /// `max_concurrent_jobs`. At least one job is always allowed.
+    pub fn free_job_slots(&self) -> usize {
+        (self.max_concurrent_jobs.max(1) as usize).saturating_sub(self.active_jobs.len())
+    }
+
+    /// Records the transaction that sent the job result.
+    pub fn record_job_submitted(&mut self, source: &LogSource, submission: Submission) {
+        let job = self.pending_job_mut(source);
+        job.status = JobStatus::Submitted;
+        job.transaction_hash = Some(submission.latest_transaction_hash());
+        job.submission = Some(submission);
+    }
+
+    /// Records a transaction that replaced a stuck transaction with the same nonce.
+    pub fn record_transaction_replaced(&mut self, source: &LogSource, submission: Submission) {
+        let transaction_hash = submission.latest_transaction_hash();
+        self.nonces.record_sent(submission.nonce, transaction_hash);
+        let job = self.pending_job_mut(source);
+        job.transaction_hash = Some(transaction_hash);
+        job.submission = Some(submission);
+    }
+
+    /// Forgets the transaction of a job that is no longer known to the node, so that
+    /// the next attempt sends the result again. The transaction's nonce is released.
+    /// The drop is recorded as a failed attempt with [`State::record_job_failure`].
+    pub fn record_job_dropped(&mut self, source: &LogSource) {
+        let job = self.pending_job_mut(source);
+        job.transaction_hash = None;
+        if let Some(submission) = job.submission.take() {
+            self.nonces
+                .record_dropped(submission.nonce, submission.latest_transaction_hash());
+        }
+    }
+
+    /// Moves a job whose result transaction reverted to the dead letter list.
+    pub fn record_job_reverted(
+        &mut self,
+        source: &LogSource,
+        transaction_hash: FixedBytes<32>,
+        receipt: Receipt,
+    ) {
+        let mut job = self
+            .logs_to_process
+            .remove(source)
+            .unwrap_or_else(|| panic!("attempted to revert an unknown job {source:?}"));
+        if let Some(submission) = &job.submission {
+            self.nonces.record_confirmed(submission.nonce);
+        }
+        job.status = JobStatus::Failed;
+        job.transaction_hash = Some(transaction_hash);
+        job.receipt = Some(receipt);
+        job.last_error = Some(JobError::Reverted(transaction_hash));
+        self.dead_letter_jobs.insert(source.clone(), job);
+        self.metrics.jobs_failed += 1;
+    }
+
+    /// Moves a job whose result transaction was mined from `logs_to_process` to the
+    /// processed logs in stable memory.
+    pub fn record_job_confirmed(
+        &mut self,
+        source: &LogSource,
+        transaction_hash: FixedBytes<32>,
+        receipt: Receipt,
+    ) {
+        let job = match self.logs_to_process.remove(source) {
+            Some(job) => job,
+            None => panic!("attempted to confirm an unknown job {source:?}"),
+        };
+        if let Some(submission) = &job.submission {
+            self.nonces.record_confirmed(submission.nonce);
+        }
+        self.metrics.jobs_succeeded += 1;
+
+        let processed_log = ProcessedLog {
+            block_number: job.log.block_number.unwrap_or_default(),
+            job_id: job.job_id.map(|job_id| job_id.to_be_bytes()),
+            status: JobStatus::Confirmed,
+            transaction_hash: Some(transaction_hash.0),
+            receipt: Some(receipt),
+            attempts: job.attempts,
+            result: job.result.map(|result| result.to_vec()),
+        };
+        // a log can only be processed again if it was reorged before
+        if let Some(previous) = storage::record_processed_log(source.clone(), processed_log) {
+            assert_eq!(
+                previous.status,
+                JobStatus::Reorged,
+                "attempted to run job twice for the same event {source:?}"
+            );
+        }
+    }
+
+    /// Records a failed attempt to run the job.
+    ///
+    /// Returns the delay after which the job should be retried, or `None` if the job
+    /// ran out of attempts or failed with an error that is not retriable, in which case
+    /// it was moved to the dead letter list.
+    pub fn record_job_failure(
+        &mut self,
+        source: &LogSource,
+        error: JobError,
+        now: u64,
+    ) -> Option<Duration> {
+        let job = self.pending_job_mut(source);
+        let retriable = error.is_retriable();
+        job.last_error = Some(error);
+        if !retriable || job.attempts >= MAX_JOB_ATTEMPTS {
+            let mut job = self
+                .logs_to_process
+                .remove(source)
+                .expect("BUG: job not found");
+            job.status = JobStatus::Failed;
+            self.dead_letter_jobs.insert(source.clone(), job);
+            self.metrics.jobs_failed += 1;
+            return None;
+        }
+        let delay = retry_delay(job.attempts);
+        job.status = JobStatus::Pending;
+        job.next_attempt_at = now.saturating_add(delay.as_nanos() as u64);
+        self.metrics.jobs_retried += 1;
+        Some(delay)
+    }
+
+    /// Moves a job from the dead letter list back to `logs_to_process` and resets its
+    /// attempts.
+    pub fn requeue_dead_letter_job(&mut self, source: &LogSource) -> Result<(), String> {
+        let mut job = self
+            .dead_letter_jobs
+            .remove(source)
+            .ok_or_else(|| format!("no dead letter job for {source:?}"))?;
+        // the result of a reverted transaction is sent again
+        if job.receipt.take().is_some() {
+            job.transaction_hash = None;
+            job.submission = None;
+        }
+        self.logs_to_process.insert(
+            source.clone(),
+            Job {
+                status: if job.submission.is_some() {
+                    JobStatus::Submitted
+                } else {
+                    JobStatus::Pending
+                },
+                attempts: 0,
+                next_attempt_at: 0,
+                ..job
+            },
         );
+        Ok(())
+    }
+
+    /// Returns the pending or dead letter job with the given source.
+    pub fn get_job(&self, source: &LogSource) -> Option<&Job> {
+        self.logs_to_process
+            .get(source)
+            .or_else(|| self.dead_letter_jobs.get(source))
+    }
+
+    /// Returns the pending or dead letter job with the given id.
+    pub fn find_job_by_id(&self, job_id: U256) -> Option<(&LogSource, &Job)> {
+        self.logs_to_process
+            .iter()
+            .chain(self.dead_letter_jobs.iter())
+            .find(|(_, job)| job.job_id == Some(job_id))
+    }
+
+    fn pending_job_mut(&mut self, source: &LogSource) -> &mut Job {
+        self.logs_to_process
+            .get_mut(source)
+            .unwrap_or_else(|| panic!("BUG: no pending job for {source:?}"))
+    }
+
+    /// Removes processed logs that are more than `processed_logs_retention_blocks`
+    /// blocks older than the head block.
+    pub fn prune_processed_logs(&mut self, head_block_number: u64) {
+        if let Some(retention) = self.processed_logs_retention_blocks {
+            let min_block_number = head_block_number.saturating_sub(retention);
+            storage::prune_processed_logs(min_block_number);
+            self.processed_logs_pruned_below =
+                self.processed_logs_pruned_below.max(min_block_number);
+        }
     }
 
     pub fn has_logs_to_process(&self) -> bool {
         !self.logs_to_process.is_empty()
     }
 
-    pub fn key_id(&self) -> EcdsaKeyId {
-        self.ecdsa_key_id.clone()
+    /// Returns the first block that has not been scraped yet, or `None` if scraping
+    /// should start at the latest block.
+    pub fn next_block_to_scrape(&self) -> Option<u64> {
+        match self.last_scraped_block_number {
+            Some(block_number) => Some(block_number + 1),
+            None => self.start_block,
+        }
+    }
+
+    /// Returns the delay before the next scrape, given whether the last scrape found
+    /// new logs.
+    pub fn next_polling_delay(&mut self, found_logs: bool) -> Duration {
+        match self.polling_interval {
+            PollingInterval::Fixed { secs } => Duration::from_secs(secs),
+            PollingInterval::Adaptive { min_secs, max_secs } => {
+                let min = Duration::from_secs(min_secs);
+                let max = Duration::from_secs(max_secs);
+                let delay = match self.polling_delay {
+                    Some(delay) if !found_logs => delay.saturating_mul(2).clamp(min, max),
+                    _ => min,
+                };
+                self.polling_delay = Some(delay);
+                delay
+            }
+        }
+    }
+
+    /// Grows the block spread after a successful `eth_getLogs` call and lowers a
+    /// raised response size limit by a quarter, back towards the default, so that a
+    /// single large block does not raise the cycles cost of every later call.
+    pub fn grow_block_spread(&mut self) {
+        self.block_spread = (self.block_spread + self.block_spread / 2)
+            .max(self.block_spread + 1)
+            .min(MAX_BLOCK_SPREAD);
+        self.logs_response_size =
+            (self.logs_response_size - self.logs_response_size / 4).max(DEFAULT_LOGS_RESPONSE_SIZE);
+    }
+
+    /// Returns whether a range of `spread` blocks can be split or its response size
+    /// limit raised.
+    pub fn can_shrink_block_spread(&self, spread: u64) -> bool {
+        spread > 1 || self.logs_response_size < MAX_LOGS_RESPONSE_SIZE
+    }
+
+    /// Halves the block spread after the providers rejected a range of `spread` blocks
+    /// as too large. Once a single block is too large, the response size limit is
+    /// doubled instead.
+    pub fn shrink_block_spread(&mut self, spread: u64) {
+        if spread > 1 {
+            self.block_spread = spread / 2;
+        } else {
+            self.logs_response_size = (self.logs_response_size * 2).min(MAX_LOGS_RESPONSE_SIZE);
+        }
+    }
+
+    /// Schedules the blocks `from_block..=to_block` to be scraped again. Only blocks
+    /// that were scraped before and whose processed logs were not pruned can be
+    /// rescanned, otherwise jobs that already ran would run again.
+    pub fn add_rescan(&mut self, from_block: u64, to_block: u64) -> Result<(), String> {
+        if from_block > to_block {
+            return Err(format!(
+                "from_block {from_block} must not be after to_block {to_block}"
+            ));
+        }
+        if from_block < self.processed_logs_pruned_below {
+            return Err(format!(
+                "the processed logs before block {} were pruned, blocks before it cannot \
+                 be rescanned",
+                self.processed_logs_pruned_below
+            ));
+        }
+        if self
+            .last_scraped_block_number
+            .is_none_or(|last_scraped| to_block > last_scraped)
+        {
+            return Err(format!(
+                "block {to_block} was not scraped yet, the last scraped block is {:?}",
+                self.last_scraped_block_number
+            ));
+        }
+        self.rescans.push_back(BlockRange {
+            from_block,
+            to_block,
+        });
+        Ok(())
+    }
+
+    /// Records that the blocks of the next rescan were scraped up to `to_block`.
+    pub fn record_rescan_progress(&mut self, to_block: u64) {
+        if let Some(range) = self.rescans.front_mut() {
+            range.from_block = to_block + 1;
+            if range.from_block > range.to_block {
+                self.rescans.pop_front();
+            }
+        }
+    }
+
+    /// Returns the providers logs are scraped from.
+    pub fn log_rpc_services(&self) -> Vec<RpcService> {
+        if self.rpc_services.is_empty() {
+            vec![self.rpc_service.clone()]
+        } else {
+            self.rpc_services.clone()
+        }
+    }
+
+    /// Returns how many log providers have to return a log before it is accepted.
+    pub fn min_agreeing_providers(&self) -> usize {
+        match self.consensus {
+            ConsensusStrategy::Equality => self.log_rpc_services().len(),
+            ConsensusStrategy::Threshold { min, .. } => min as usize,
+        }
+    }
+
+    /// Records a disagreement between the log providers, dropping the oldest entries
+    /// beyond `MAX_INCONSISTENT_RESPONSES`.
+    pub fn record_inconsistent_response(&mut self, response: InconsistentResponse) {
+        self.inconsistent_responses.push_back(response);
+        while self.inconsistent_responses.len() > MAX_INCONSISTENT_RESPONSES {
+            self.inconsistent_responses.pop_front();
+        }
+    }
+
+    /// Appends a change to the configuration audit trail, dropping the oldest entries
+    /// beyond `MAX_CONFIG_CHANGES`.
+    pub fn record_config_change(&mut self, change: ConfigChange) {
+        self.config_changes.push_back(change);
+        while self.config_changes.len() > MAX_CONFIG_CHANGES {
+            self.config_changes.pop_front();
+        }
+    }
+
+    /// Records the error as the last error of the task.
+    pub fn record_task_error(&mut self, task: TaskType, message: String, now: u64) {
+        self.last_errors.insert(
+            task,
+            TaskError {
+                task,
+                timestamp: now,
+                message,
+            },
+        );
+    }
+
+    /// Returns the selector of the function job results are sent to.
+    pub fn callback_selector(&self) -> [u8; 4] {
+        let hash = keccak256(self.callback_signature.as_bytes());
+        [hash[0], hash[1], hash[2], hash[3]]
     }
 
-    pub fn get_filter_addresses(&self) -> Vec<Address> {
-        self.filter_addresses.clone()
+    pub fn key_id(&self) -> EcdsaKeyId {
+        self.ecdsa_key_id.clone()
     }
 
-    pub fn get_filter_events(&self) -> Vec<String> {
-        self.filter_events.clone()
+    /// Returns the filter of the scraped logs, without a block range.
+    pub fn log_filter(&self) -> Filter {
+        let mut filter = Filter::new()
+            .address(self.filter_addresses.clone())
+            // By specifying an `event` or `event_signature` we listen for a specific event of the
+            // contract. In this case the `Transfer(address,address,uint256)` event.
+            // .event(Coprocessor::NewJob::SIGNATURE)
+            .events(&self.filter_events);
+        if let Some(topic1) = &self.filter_topics.topic1 {
+            filter = filter.topic1(topic1.clone());
+        }
+        if let Some(topic2) = &self.filter_topics.topic2 {
+            filter = filter.topic2(topic2.clone());
+        }
+        if let Some(topic3) = &self.filter_topics.topic3 {
+            filter = filter.topic3(topic3.clone());
+        }
+        filter
     }
 }
 
@@ -94,7 +1049,7 @@ impl IntoLogSource for Log {
 
 /// A unique identifier of the event source: the source transaction hash and the log
 /// entry index.
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct LogSource {
     pub transaction_hash: FixedBytes<32>,
     pub log_index: u64,
@@ -119,8 +1074,559 @@ pub fn initialize_state(state: State) {
     STATE.set(Some(state));
 }
 
-#[derive(Debug, Hash, Copy, Clone, PartialEq, Eq)]
+#[derive(CandidType, Debug, Hash, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
 pub enum TaskType {
     ProcessLogs,
     ScrapeLogs,
+    SubmitResults,
+    TrackTransactions,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{log, state};
+
+    fn source(log_index: u64) -> LogSource {
+        LogSource {
+            transaction_hash: B256::repeat_byte(2),
+            log_index,
+        }
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn queue(state: &mut State, callback: Callback, queued_at: u64) {
+        let log_index = state.submission_queue.len() as u64;
+        state.submission_queue.push_back(QueuedCallback {
+            log_source: source(log_index),
+            callback,
+            queued_at,
+        });
+    }
+
+    fn result(job_id: u64) -> Callback {
+        Callback::result(U256::from(job_id), U256::from(job_id))
+    }
+
+    fn calldata(job_id: u64) -> Callback {
+        Callback::calldata(Some(U256::from(job_id)), None, Bytes::new())
+    }
+
+    fn submission(nonce: u64, transaction_hash: B256) -> Submission {
+        Submission {
+            to: Address::ZERO,
+            input: Bytes::new(),
+            nonce,
+            max_fee_per_gas: 1,
+            max_priority_fee_per_gas: 1,
+            transaction_hashes: vec![transaction_hash],
+            sent_at: 0,
+        }
+    }
+
+    fn job_ids(callbacks: Vec<QueuedCallback>) -> Vec<u64> {
+        callbacks
+            .into_iter()
+            .map(|queued| queued.callback.job_id.unwrap().to::<u64>())
+            .collect()
+    }
+
+    #[test]
+    fn should_double_retry_delay_up_to_maximum() {
+        assert_eq!(retry_delay(0), JOB_RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(1), JOB_RETRY_BASE_DELAY);
+        assert_eq!(retry_delay(2), JOB_RETRY_BASE_DELAY * 2);
+        assert_eq!(retry_delay(3), JOB_RETRY_BASE_DELAY * 4);
+        assert_eq!(retry_delay(20), JOB_RETRY_MAX_DELAY);
+        assert_eq!(retry_delay(u32::MAX), JOB_RETRY_MAX_DELAY);
+    }
+
+    #[test]
+    fn should_combine_paused_modes() {
+        assert_eq!(Mode::Running.pause_scraping(), Mode::ScrapingPaused);
+        assert_eq!(Mode::ProcessingPaused.pause_scraping(), Mode::Paused);
+        assert_eq!(Mode::Draining.pause_scraping(), Mode::Draining);
+        assert_eq!(Mode::Paused.pause_scraping(), Mode::Paused);
+
+        assert_eq!(Mode::Running.pause_processing(), Mode::ProcessingPaused);
+        assert_eq!(Mode::ScrapingPaused.pause_processing(), Mode::Paused);
+        assert_eq!(Mode::Draining.pause_processing(), Mode::Paused);
+        assert_eq!(Mode::Paused.pause_processing(), Mode::Paused);
+    }
+
+    #[test]
+    fn should_pause_tasks_by_mode() {
+        use TaskType::*;
+        for (mode, paused) in [
+            (Mode::Running, vec![]),
+            (Mode::ScrapingPaused, vec![ScrapeLogs]),
+            (Mode::ProcessingPaused, vec![ProcessLogs, SubmitResults]),
+            (Mode::Paused, vec![ScrapeLogs, ProcessLogs, SubmitResults]),
+            (Mode::Draining, vec![ScrapeLogs]),
+        ] {
+            for task in [ScrapeLogs, ProcessLogs, SubmitResults, TrackTransactions] {
+                assert_eq!(
+                    mode.pauses(task),
+                    paused.contains(&task),
+                    "{mode:?} {task:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn should_only_retry_started_jobs_while_draining() {
+        let mut state = state();
+        for log_index in 0..3 {
+            state.record_log_to_process(&log(log_index));
+        }
+        state.record_job_attempt(&source(0));
+        state.record_job_failure(&source(0), JobError::Rpc("timeout".to_string()), 0);
+        state.record_job_attempt(&source(1));
+        assert_eq!(state.in_flight_jobs(), 2);
+
+        state.mode = Mode::Draining;
+        let retry_at = u64::MAX;
+        let jobs = state.jobs_to_run(retry_at);
+        assert_eq!(
+            jobs.into_iter()
+                .map(|(source, _)| source)
+                .collect::<Vec<_>>(),
+            vec![source(0)]
+        );
+        assert!(state.jobs_to_run(0).is_empty());
+
+        state.mode = Mode::Running;
+        assert_eq!(state.jobs_to_run(retry_at).len(), 2);
+    }
+
+    #[test]
+    fn should_send_callbacks_one_by_one_without_batching() {
+        let mut state = state();
+        queue(&mut state, result(1), 0);
+        queue(&mut state, result(2), 0);
+        assert_eq!(state.take_callbacks_to_send(0).map(job_ids), Ok(vec![1]));
+        assert_eq!(state.take_callbacks_to_send(0).map(job_ids), Ok(vec![2]));
+        assert_eq!(state.take_callbacks_to_send(0), Ok(vec![]));
+    }
+
+    #[test]
+    fn should_wait_for_batch_window() {
+        let mut state = state();
+        state.batch = Some(BatchConfig {
+            max_size: 3,
+            window_secs: 10,
+        });
+        queue(&mut state, result(1), 5 * SECOND);
+        queue(&mut state, result(2), 7 * SECOND);
+        assert_eq!(state.take_callbacks_to_send(14 * SECOND), Err(15 * SECOND));
+        assert_eq!(
+            state.take_callbacks_to_send(15 * SECOND).map(job_ids),
+            Ok(vec![1, 2])
+        );
+    }
+
+    #[test]
+    fn should_send_full_batch_immediately() {
+        let mut state = state();
+        state.batch = Some(BatchConfig {
+            max_size: 2,
+            window_secs: 10,
+        });
+        for job_id in 1..=3 {
+            queue(&mut state, result(job_id), 0);
+        }
+        assert_eq!(state.take_callbacks_to_send(0).map(job_ids), Ok(vec![1, 2]));
+        assert_eq!(state.take_callbacks_to_send(0), Err(10 * SECOND));
+    }
+
+    #[test]
+    fn should_not_batch_calldata_callbacks() {
+        let mut state = state();
+        state.batch = Some(BatchConfig {
+            max_size: 3,
+            window_secs: 10,
+        });
+        queue(&mut state, calldata(1), 0);
+        queue(&mut state, result(2), 0);
+        queue(&mut state, calldata(3), 0);
+        assert_eq!(state.take_callbacks_to_send(0).map(job_ids), Ok(vec![1]));
+        // the batch ends at the next callback that cannot be batched
+        assert_eq!(state.take_callbacks_to_send(0), Err(10 * SECOND));
+        assert_eq!(
+            state.take_callbacks_to_send(10 * SECOND).map(job_ids),
+            Ok(vec![2])
+        );
+        assert_eq!(state.take_callbacks_to_send(0).map(job_ids), Ok(vec![3]));
+    }
+
+    #[test]
+    fn should_use_fixed_polling_interval() {
+        let mut state = state();
+        state.polling_interval = PollingInterval::Fixed { secs: 12 };
+        assert_eq!(state.next_polling_delay(false), Duration::from_secs(12));
+        assert_eq!(state.next_polling_delay(true), Duration::from_secs(12));
+    }
+
+    #[test]
+    fn should_back_off_adaptive_polling_until_logs_are_found() {
+        let mut state = state();
+        state.polling_interval = PollingInterval::Adaptive {
+            min_secs: 2,
+            max_secs: 10,
+        };
+        let delays: Vec<_> = [false, false, false, false, false, true, false]
+            .into_iter()
+            .map(|found_logs| state.next_polling_delay(found_logs).as_secs())
+            .collect();
+        assert_eq!(delays, vec![2, 4, 8, 10, 10, 2, 4]);
+    }
+
+    #[test]
+    fn should_not_queue_dead_letter_job_again_on_rescan() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_job_attempt(&source(0));
+        state.record_job_failure(&source(0), JobError::Decoding("invalid".to_string()), 0);
+        assert!(state.dead_letter_jobs.contains_key(&source(0)));
+
+        state.last_scraped_block_number = Some(100);
+        assert_eq!(state.add_rescan(100, 100), Ok(()));
+        state.record_log_to_process(&log(0));
+        // the log is delivered in another block after a reorg
+        state.record_log_to_process(&Log {
+            block_hash: Some(B256::repeat_byte(3)),
+            ..log(0)
+        });
+
+        assert!(state.logs_to_process.is_empty());
+        assert_eq!(state.dead_letter_jobs.len(), 1);
+    }
+
+    #[test]
+    fn should_grow_block_spread_up_to_maximum() {
+        let mut state = state();
+        state.block_spread = 1;
+        state.grow_block_spread();
+        assert_eq!(state.block_spread, 2);
+        state.grow_block_spread();
+        assert_eq!(state.block_spread, 3);
+        state.grow_block_spread();
+        assert_eq!(state.block_spread, 4);
+
+        state.block_spread = MAX_BLOCK_SPREAD - 1;
+        state.grow_block_spread();
+        assert_eq!(state.block_spread, MAX_BLOCK_SPREAD);
+        state.grow_block_spread();
+        assert_eq!(state.block_spread, MAX_BLOCK_SPREAD);
+    }
+
+    #[test]
+    fn should_shrink_block_spread_then_raise_response_size() {
+        let mut state = state();
+        state.block_spread = 100;
+        state.shrink_block_spread(5);
+        assert_eq!(state.block_spread, 2);
+        state.shrink_block_spread(2);
+        assert_eq!(state.block_spread, 1);
+        assert_eq!(state.logs_response_size, DEFAULT_LOGS_RESPONSE_SIZE);
+
+        assert!(state.can_shrink_block_spread(1));
+        state.shrink_block_spread(1);
+        assert_eq!(state.block_spread, 1);
+        assert_eq!(state.logs_response_size, 2 * DEFAULT_LOGS_RESPONSE_SIZE);
+
+        while state.can_shrink_block_spread(1) {
+            state.shrink_block_spread(1);
+        }
+        assert_eq!(state.logs_response_size, MAX_LOGS_RESPONSE_SIZE);
+        assert!(state.can_shrink_block_spread(2));
+    }
+
+    #[test]
+    fn should_lower_raised_response_size_after_success() {
+        let mut state = state();
+        state.logs_response_size = MAX_LOGS_RESPONSE_SIZE;
+        state.grow_block_spread();
+        assert_eq!(state.logs_response_size, MAX_LOGS_RESPONSE_SIZE * 3 / 4);
+        for _ in 0..20 {
+            state.grow_block_spread();
+        }
+        assert_eq!(state.logs_response_size, DEFAULT_LOGS_RESPONSE_SIZE);
+    }
+
+    #[test]
+    fn should_run_interrupted_jobs_again_after_upgrade() {
+        let mut state = state();
+        for log_index in 0..3 {
+            state.record_log_to_process(&log(log_index));
+            state.record_job_attempt(&source(log_index));
+        }
+        // job 2 was taken from the queue to be sent, the callback of job 1 is queued
+        state.record_job_computed(&source(2), result(2), 0);
+        state.record_job_computed(&source(1), result(1), 0);
+        state.take_callbacks_to_send(0).unwrap();
+        state.nonces.sync(0);
+        state.nonces.reserve();
+
+        state.recover_interrupted_jobs();
+
+        let status = |state: &State, log_index| state.logs_to_process[&source(log_index)].status;
+        assert_eq!(status(&state, 0), JobStatus::Pending);
+        assert_eq!(status(&state, 1), JobStatus::Submitting);
+        assert_eq!(status(&state, 2), JobStatus::Pending);
+        assert_eq!(state.jobs_to_run(0).len(), 2);
+        assert!(!state.nonces.is_synced());
+    }
+
+    #[test]
+    fn should_dead_letter_job_whose_transactions_keep_being_dropped() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.nonces.sync(0);
+        for attempt in 1..=MAX_JOB_ATTEMPTS {
+            state.record_job_attempt(&source(0));
+            let nonce = state.nonces.reserve().unwrap();
+            let transaction_hash = B256::with_last_byte(attempt as u8);
+            state.nonces.record_sent(nonce, transaction_hash);
+            state.record_job_submitted(&source(0), submission(nonce, transaction_hash));
+
+            state.record_job_dropped(&source(0));
+            let retry = state.record_job_failure(
+                &source(0),
+                JobError::TransactionNotFound(transaction_hash),
+                0,
+            );
+            // the nonce of the dropped transaction is used again
+            assert_eq!(nonce, 0);
+            assert_eq!(retry.is_some(), attempt < MAX_JOB_ATTEMPTS);
+        }
+        assert!(state.logs_to_process.is_empty());
+        assert_eq!(
+            state.dead_letter_jobs[&source(0)].last_error,
+            Some(JobError::TransactionNotFound(B256::with_last_byte(
+                MAX_JOB_ATTEMPTS as u8
+            )))
+        );
+    }
+
+    #[test]
+    fn should_ignore_log_delivered_again_in_same_block() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_job_attempt(&source(0));
+        state.record_log_to_process(&log(0));
+        assert_eq!(state.logs_to_process[&source(0)].attempts, 1);
+        assert_eq!(state.metrics.jobs_received, 1);
+    }
+
+    #[test]
+    fn should_replace_pending_log_delivered_in_another_block() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_log_to_process(&log(1));
+        state.record_job_attempt(&source(1));
+
+        let reorged = |log_index| Log {
+            block_hash: Some(B256::repeat_byte(3)),
+            block_number: Some(101),
+            ..log(log_index)
+        };
+        state.record_log_to_process(&reorged(0));
+        state.record_log_to_process(&reorged(1));
+
+        assert_eq!(state.logs_to_process[&source(0)].log, reorged(0));
+        // a started job is left to finish
+        assert_eq!(state.logs_to_process[&source(1)].log, log(1));
+    }
+
+    #[test]
+    fn should_drop_pending_log_when_removed() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_log_to_process(&log(1));
+        state.record_job_attempt(&source(1));
+
+        for log_index in 0..3 {
+            state.record_log_to_process(&Log {
+                removed: true,
+                ..log(log_index)
+            });
+        }
+
+        assert!(!state.logs_to_process.contains_key(&source(0)));
+        assert!(state.logs_to_process.contains_key(&source(1)));
+        assert!(!state.logs_to_process.contains_key(&source(2)));
+    }
+
+    #[test]
+    fn should_drop_reorged_log_and_rewind_cursor() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.last_scraped_block_number = Some(120);
+
+        assert!(state.record_reorged_log(&source(0)));
+
+        assert!(state.logs_to_process.is_empty());
+        assert_eq!(state.last_scraped_block_number, Some(99));
+        assert_eq!(
+            storage::get_processed_log(&source(0)).map(|processed_log| processed_log.status),
+            Some(JobStatus::Reorged)
+        );
+        // the log is processed if it is scraped again
+        state.record_log_to_process(&log(0));
+        assert!(state.logs_to_process.contains_key(&source(0)));
+    }
+
+    #[test]
+    fn should_not_rewind_cursor_before_reorged_block() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.last_scraped_block_number = Some(90);
+        assert!(state.record_reorged_log(&source(0)));
+        assert_eq!(state.last_scraped_block_number, Some(90));
+    }
+
+    #[test]
+    fn should_keep_started_jobs_when_reorged() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_log_to_process(&log(1));
+        state.record_job_attempt(&source(0));
+        state.active_jobs.insert(source(1));
+        state.last_scraped_block_number = Some(120);
+
+        assert!(!state.record_reorged_log(&source(0)));
+        assert!(!state.record_reorged_log(&source(1)));
+        assert!(!state.record_reorged_log(&source(2)));
+
+        assert_eq!(state.logs_to_process.len(), 2);
+        assert_eq!(state.last_scraped_block_number, Some(120));
+    }
+
+    #[test]
+    fn should_only_prune_processed_logs_with_retention() {
+        let mut state = state();
+        state.prune_processed_logs(1_000);
+        assert_eq!(state.processed_logs_pruned_below, 0);
+
+        state.processed_logs_retention_blocks = Some(100);
+        state.prune_processed_logs(1_000);
+        assert_eq!(state.processed_logs_pruned_below, 900);
+        // the watermark never moves back, e.g. if the retention is raised
+        state.processed_logs_retention_blocks = Some(500);
+        state.prune_processed_logs(1_000);
+        assert_eq!(state.processed_logs_pruned_below, 900);
+        assert!(state.add_rescan(899, 950).is_err());
+    }
+
+    #[test]
+    fn should_not_requeue_unknown_dead_letter_job() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        assert!(state.requeue_dead_letter_job(&source(0)).is_err());
+        assert!(state.requeue_dead_letter_job(&source(1)).is_err());
+    }
+
+    #[test]
+    fn should_requeue_dead_letter_job_as_pending() {
+        let mut state = state();
+        state.record_log_to_process(&log(0));
+        state.record_job_attempt(&source(0));
+        state.record_job_failure(&source(0), JobError::Decoding("invalid".to_string()), 0);
+
+        assert_eq!(state.requeue_dead_letter_job(&source(0)), Ok(()));
+
+        assert!(state.dead_letter_jobs.is_empty());
+        let job = &state.logs_to_process[&source(0)];
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.attempts, 0);
+        assert_eq!(job.next_attempt_at, 0);
+        assert_eq!(state.jobs_to_run(0).len(), 1);
+    }
+
+    #[test]
+    fn should_send_result_of_reverted_job_again_when_requeued() {
+        let mut state = state();
+        let transaction_hash = B256::with_last_byte(1);
+        state.record_log_to_process(&log(0));
+        state.record_job_attempt(&source(0));
+        state.record_job_submitted(&source(0), submission(0, transaction_hash));
+        state.record_job_reverted(
+            &source(0),
+            transaction_hash,
+            Receipt {
+                succeeded: false,
+                gas_used: 21_000,
+                effective_gas_price: 1,
+            },
+        );
+        assert_eq!(state.dead_letter_jobs[&source(0)].status, JobStatus::Failed);
+
+        assert_eq!(state.requeue_dead_letter_job(&source(0)), Ok(()));
+
+        let job = &state.logs_to_process[&source(0)];
+        assert_eq!(job.status, JobStatus::Pending);
+        assert_eq!(job.attempts, 0);
+        assert_eq!(job.transaction_hash, None);
+        assert_eq!(job.submission, None);
+        assert_eq!(job.receipt, None);
+    }
+
+    #[test]
+    fn should_keep_tracking_sent_transaction_when_requeued() {
+        let mut state = state();
+        let transaction_hash = B256::with_last_byte(1);
+        state.dead_letter_jobs.insert(
+            source(0),
+            Job {
+                status: JobStatus::Failed,
+                attempts: MAX_JOB_ATTEMPTS,
+                transaction_hash: Some(transaction_hash),
+                submission: Some(submission(0, transaction_hash)),
+                ..Job::new(log(0))
+            },
+        );
+
+        assert_eq!(state.requeue_dead_letter_job(&source(0)), Ok(()));
+
+        let job = &state.logs_to_process[&source(0)];
+        assert_eq!(job.status, JobStatus::Submitted);
+        assert_eq!(job.transaction_hash, Some(transaction_hash));
+        assert_eq!(job.submission, Some(submission(0, transaction_hash)));
+        assert!(state.jobs_to_run(0).is_empty());
+    }
+
+    #[test]
+    fn should_page_through_pending_and_dead_letter_jobs() {
+        let mut state = state();
+        for log_index in [0, 2, 4] {
+            state.record_log_to_process(&log(log_index));
+        }
+        for log_index in [1, 3] {
+            state.dead_letter_jobs.insert(
+                source(log_index),
+                Job {
+                    status: JobStatus::Failed,
+                    ..Job::new(log(log_index))
+                },
+            );
+        }
+        let page = |from: Option<u64>, limit: usize| {
+            let (jobs, next) = state.list_pending_jobs(from.map(source).as_ref(), limit);
+            let log_indexes: Vec<u64> = jobs.iter().map(|(source, _)| source.log_index).collect();
+            (log_indexes, next.map(|next| next.log_index))
+        };
+
+        assert_eq!(page(None, 2), (vec![0, 1], Some(2)));
+        assert_eq!(page(Some(2), 2), (vec![2, 3], Some(4)));
+        assert_eq!(page(Some(4), 2), (vec![4], None));
+        // a cursor on a dead letter job
+        assert_eq!(page(Some(1), 2), (vec![1, 2], Some(3)));
+        assert_eq!(page(Some(3), 1), (vec![3], Some(4)));
+        assert_eq!(page(None, 5), (vec![0, 1, 2, 3, 4], None));
+        assert_eq!(page(Some(5), 2), (vec![], None));
+        assert_eq!(page(None, 0), (vec![], Some(0)));
+    }
 }
@@ -1,14 +1,28 @@
+use alloy::primitives::FixedBytes;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
     storable::Storable,
-    DefaultMemoryImpl, StableBTreeMap,
+    writer::Writer,
+    DefaultMemoryImpl, Memory, StableBTreeMap,
 };
 use minicbor_derive::{Decode, Encode};
 use std::borrow::Cow;
 use std::cell::RefCell;
 
+use crate::logger::LogLevel;
+use crate::state::{JobStatus, LogSource, Receipt, State};
+
 const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PROCESSED_LOGS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const JOB_IDS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const LOG_ENTRIES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const PROCESSED_LOGS_BY_BLOCK_MEMORY_ID: MemoryId = MemoryId::new(5);
+
+/// Size of a serialized [`LogSource`]: the 32 byte transaction hash followed by
+/// the log index as a big endian `u64`.
+const LOG_SOURCE_SIZE: usize = 40;
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 
@@ -33,8 +47,172 @@ impl Storable for Asset {
     }
 
     fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        minicbor::decode(bytes.as_ref())
-            .unwrap_or_else(|e| panic!("failed to decode asset bytes {}: {e}", hex::encode(bytes)))
+        minicbor::decode(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode asset bytes {}: {e}",
+                alloy::hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for LogSource {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(LOG_SOURCE_SIZE);
+        buf.extend_from_slice(self.transaction_hash.as_slice());
+        // big endian keeps the byte order consistent with the `Ord` implementation
+        buf.extend_from_slice(&self.log_index.to_be_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        assert_eq!(bytes.len(), LOG_SOURCE_SIZE, "invalid log source length");
+        let (transaction_hash, log_index) = bytes.split_at(32);
+        Self {
+            transaction_hash: FixedBytes::from_slice(transaction_hash),
+            log_index: u64::from_be_bytes(log_index.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: LOG_SOURCE_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// A job id as a big endian `uint256`, the key of the index of processed logs by job id.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct JobId([u8; 32]);
+
+impl Storable for JobId {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.as_ref().try_into().expect("invalid job id length"))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// The key of the index of processed logs by block number: the block number as a big
+/// endian `u64` followed by the serialized [`LogSource`], so that the entries are
+/// ordered by block.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct BlockLogSource {
+    block_number: u64,
+    source: LogSource,
+}
+
+impl Storable for BlockLogSource {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = Vec::with_capacity(8 + LOG_SOURCE_SIZE);
+        buf.extend_from_slice(&self.block_number.to_be_bytes());
+        buf.extend_from_slice(&self.source.to_bytes());
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        assert_eq!(
+            bytes.len(),
+            8 + LOG_SOURCE_SIZE,
+            "invalid block log source length"
+        );
+        let (block_number, source) = bytes.split_at(8);
+        Self {
+            block_number: u64::from_be_bytes(block_number.try_into().unwrap()),
+            source: LogSource::from_bytes(Cow::Borrowed(source)),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 8 + LOG_SOURCE_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+/// A compact record of a processed log.
+#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+pub struct ProcessedLog {
+    #[n(0)]
+    pub block_number: u64,
+    /// The job id as a big endian `uint256`, `None` if the log was never decoded.
+    #[cbor(n(1), with = "minicbor::bytes")]
+    pub job_id: Option<[u8; 32]>,
+    #[n(2)]
+    pub status: JobStatus,
+    /// The hash of the transaction that submitted the job result.
+    #[cbor(n(3), with = "minicbor::bytes")]
+    pub transaction_hash: Option<[u8; 32]>,
+    #[n(4)]
+    pub receipt: Option<Receipt>,
+    /// The number of attempts to run the job.
+    #[n(5)]
+    pub attempts: u32,
+    /// The ABI-encoded job result.
+    #[cbor(n(6), with = "minicbor::bytes")]
+    pub result: Option<Vec<u8>>,
+}
+
+impl Storable for ProcessedLog {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        minicbor::encode(self, &mut buf).expect("processed log encoding should always succeed");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        minicbor::decode(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode processed log bytes {}: {e}",
+                alloy::hex::encode(bytes)
+            )
+        })
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// An entry of the canister log.
+#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+pub struct LogEntry {
+    /// When the entry was recorded, in nanoseconds since the epoch.
+    #[n(0)]
+    pub timestamp: u64,
+    #[n(1)]
+    pub level: LogLevel,
+    #[n(2)]
+    pub message: String,
+    /// The transaction hash of the log whose job the entry is about.
+    #[cbor(n(3), with = "minicbor::bytes")]
+    pub transaction_hash: Option<[u8; 32]>,
+    #[n(4)]
+    pub log_index: Option<u64>,
+    /// The job id as a big endian `uint256`.
+    #[cbor(n(5), with = "minicbor::bytes")]
+    pub job_id: Option<[u8; 32]>,
+}
+
+impl Storable for LogEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        minicbor::encode(self, &mut buf).expect("log entry encoding should always succeed");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        minicbor::decode(bytes.as_ref()).unwrap_or_else(|e| {
+            panic!(
+                "failed to decode log entry bytes {}: {e}",
+                alloy::hex::encode(bytes)
+            )
+        })
     }
 
     const BOUND: Bound = Bound::Unbounded;
@@ -53,9 +231,20 @@ thread_local! {
                 )
             )
     );
+    static PROCESSED_LOGS: RefCell<StableBTreeMap<LogSource, ProcessedLog, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PROCESSED_LOGS_MEMORY_ID))));
+    static JOB_IDS: RefCell<StableBTreeMap<JobId, LogSource, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(JOB_IDS_MEMORY_ID))));
+    static LOG_ENTRIES: RefCell<StableBTreeMap<u64, LogEntry, VMem>> = MEMORY_MANAGER
+        .with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(LOG_ENTRIES_MEMORY_ID))));
+    static PROCESSED_LOGS_BY_BLOCK: RefCell<StableBTreeMap<BlockLogSource, (), VMem>> =
+        MEMORY_MANAGER.with(|m| {
+            RefCell::new(StableBTreeMap::init(m.borrow().get(PROCESSED_LOGS_BY_BLOCK_MEMORY_ID)))
+        });
 }
 
 /// Stores the asset in the stable memory.
+#[allow(dead_code)]
 pub fn store_asset(path: String, asset: Asset) {
     ASSETS.with(|assets| assets.borrow_mut().insert(path, asset));
 }
@@ -63,6 +252,272 @@ pub fn store_asset(path: String, asset: Asset) {
 /// Gets an assset from stable memory.
 /// Returns `None` if the asset is not found.
 /// Returns `Some(asset)` if the asset is found.
+#[allow(dead_code)]
 pub fn get_asset(path: &String) -> Option<Asset> {
     ASSETS.with(|assets| assets.borrow().get(path))
 }
+
+/// Records a processed log, returning the previous record for the same source if any.
+pub fn record_processed_log(
+    source: LogSource,
+    processed_log: ProcessedLog,
+) -> Option<ProcessedLog> {
+    if let Some(job_id) = processed_log.job_id {
+        JOB_IDS.with(|job_ids| job_ids.borrow_mut().insert(JobId(job_id), source.clone()));
+    }
+    let block_number = processed_log.block_number;
+    let previous =
+        PROCESSED_LOGS.with(|logs| logs.borrow_mut().insert(source.clone(), processed_log));
+    PROCESSED_LOGS_BY_BLOCK.with(|index| {
+        let mut index = index.borrow_mut();
+        // a log that was reorged may be processed again in a different block
+        if let Some(previous) = &previous {
+            index.remove(&BlockLogSource {
+                block_number: previous.block_number,
+                source: source.clone(),
+            });
+        }
+        index.insert(
+            BlockLogSource {
+                block_number,
+                source,
+            },
+            (),
+        );
+    });
+    previous
+}
+
+pub fn get_processed_log(source: &LogSource) -> Option<ProcessedLog> {
+    PROCESSED_LOGS.with(|logs| logs.borrow().get(source))
+}
+
+/// Returns the processed log of the job with the given id (as a big endian `uint256`).
+pub fn get_processed_log_by_job_id(job_id: [u8; 32]) -> Option<(LogSource, ProcessedLog)> {
+    let source = JOB_IDS.with(|job_ids| job_ids.borrow().get(&JobId(job_id)))?;
+    get_processed_log(&source).map(|processed_log| (source, processed_log))
+}
+
+/// Returns up to `limit` processed logs ordered by source, starting at `from`, and
+/// the source to continue from if there are more.
+pub fn list_processed_logs(
+    from: Option<LogSource>,
+    limit: usize,
+) -> (Vec<(LogSource, ProcessedLog)>, Option<LogSource>) {
+    PROCESSED_LOGS.with(|logs| {
+        let logs = logs.borrow();
+        let mut iter = match from {
+            Some(from) => logs.range(from..),
+            None => logs.range(..),
+        };
+        let page: Vec<_> = iter.by_ref().take(limit).collect();
+        (page, iter.next().map(|(source, _)| source))
+    })
+}
+
+/// Removes all processed logs from blocks before `min_block_number`. Only the expired
+/// entries of the index by block number are visited.
+pub fn prune_processed_logs(min_block_number: u64) {
+    let expired: Vec<BlockLogSource> = PROCESSED_LOGS_BY_BLOCK.with(|index| {
+        index
+            .borrow()
+            .iter()
+            .take_while(|(key, _)| key.block_number < min_block_number)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in expired {
+        PROCESSED_LOGS_BY_BLOCK.with(|index| index.borrow_mut().remove(&key));
+        let Some(processed_log) = PROCESSED_LOGS.with(|logs| logs.borrow_mut().remove(&key.source))
+        else {
+            continue;
+        };
+        if let Some(job_id) = processed_log.job_id {
+            JOB_IDS.with(|job_ids| {
+                let mut job_ids = job_ids.borrow_mut();
+                if job_ids.get(&JobId(job_id)) == Some(key.source) {
+                    job_ids.remove(&JobId(job_id));
+                }
+            });
+        }
+    }
+}
+
+/// Appends an entry to the canister log, dropping the oldest entries beyond
+/// `max_entries`. Entries are numbered in the order they are recorded.
+pub fn append_log_entry(entry: LogEntry, max_entries: u64) {
+    LOG_ENTRIES.with(|entries| {
+        let mut entries = entries.borrow_mut();
+        let id = entries.last_key_value().map_or(0, |(id, _)| id + 1);
+        entries.insert(id, entry);
+        while entries.len() > max_entries {
+            let (oldest, _) = entries.first_key_value().expect("BUG: log is empty");
+            entries.remove(&oldest);
+        }
+    })
+}
+
+/// Returns up to `limit` log entries matching `predicate` with an id of at least
+/// `from`, oldest first.
+pub fn get_log_entries(
+    from: u64,
+    limit: usize,
+    predicate: impl Fn(&LogEntry) -> bool,
+) -> Vec<(u64, LogEntry)> {
+    LOG_ENTRIES.with(|entries| {
+        entries
+            .borrow()
+            .range(from..)
+            .filter(|(_, entry)| predicate(entry))
+            .take(limit)
+            .collect()
+    })
+}
+
+fn get_upgrades_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADES_MEMORY_ID))
+}
+
+/// Serializes the state into the upgrades memory.
+///
+/// The state is written as its length (4 bytes, little endian) followed by
+/// its CBOR encoding.
+pub fn save_state(state: &State) {
+    let mut state_bytes = vec![];
+    ciborium::ser::into_writer(state, &mut state_bytes).expect("failed to encode state");
+    let len = u32::try_from(state_bytes.len()).expect("state does not fit into 4 GiB");
+    let mut memory = get_upgrades_memory();
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&len.to_le_bytes())
+        .expect("failed to write state length");
+    writer
+        .write(&state_bytes)
+        .expect("failed to write state to stable memory");
+}
+
+/// Deserializes the state previously written by [`save_state`].
+pub fn load_state() -> State {
+    let memory = get_upgrades_memory();
+    let mut state_len_bytes = [0; 4];
+    memory.read(0, &mut state_len_bytes);
+    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
+    let mut state_bytes = vec![0; state_len];
+    memory.read(4, &mut state_bytes);
+    ciborium::de::from_reader(&*state_bytes).expect("failed to decode state")
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::B256;
+
+    use super::*;
+    use crate::test_fixtures::{log, state};
+
+    fn source(log_index: u64) -> LogSource {
+        LogSource {
+            transaction_hash: B256::repeat_byte(2),
+            log_index,
+        }
+    }
+
+    fn processed_log(block_number: u64, job_id: u8) -> ProcessedLog {
+        ProcessedLog {
+            block_number,
+            job_id: Some(B256::with_last_byte(job_id).0),
+            status: JobStatus::Confirmed,
+            transaction_hash: None,
+            receipt: None,
+            attempts: 1,
+            result: None,
+        }
+    }
+
+    fn indexed_blocks() -> Vec<u64> {
+        PROCESSED_LOGS_BY_BLOCK.with(|index| {
+            index
+                .borrow()
+                .iter()
+                .map(|(key, _)| key.block_number)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn should_restore_pending_logs_after_upgrade() {
+        let mut state = state();
+        for log_index in 0..3 {
+            state.record_log_to_process(&log(log_index));
+        }
+        state.record_job_attempt(&LogSource {
+            transaction_hash: B256::repeat_byte(2),
+            log_index: 1,
+        });
+        state.last_scraped_block_number = Some(100);
+
+        save_state(&state);
+        let restored = load_state();
+
+        assert_eq!(restored.logs_to_process.len(), 3);
+        for ((source, job), (restored_source, restored_job)) in state
+            .logs_to_process
+            .iter()
+            .zip(restored.logs_to_process.iter())
+        {
+            assert_eq!(source, restored_source);
+            assert_eq!(job.log, restored_job.log);
+            assert_eq!(job.status, restored_job.status);
+            assert_eq!(job.attempts, restored_job.attempts);
+        }
+        assert_eq!(restored.filter_events, state.filter_events);
+        assert_eq!(restored.last_scraped_block_number, Some(100));
+        assert_eq!(restored.metrics.jobs_received, 3);
+    }
+
+    #[test]
+    fn should_prune_processed_logs_before_block() {
+        for (log_index, block_number) in [(0, 30), (1, 10), (2, 20), (3, 19)] {
+            record_processed_log(
+                source(log_index),
+                processed_log(block_number, log_index as u8),
+            );
+        }
+        assert_eq!(indexed_blocks(), vec![10, 19, 20, 30]);
+
+        prune_processed_logs(20);
+
+        assert_eq!(indexed_blocks(), vec![20, 30]);
+        assert!(get_processed_log(&source(1)).is_none());
+        assert!(get_processed_log(&source(3)).is_none());
+        assert!(get_processed_log_by_job_id(B256::with_last_byte(1).0).is_none());
+        assert_eq!(
+            get_processed_log_by_job_id(B256::with_last_byte(2).0),
+            Some((source(2), processed_log(20, 2)))
+        );
+        assert_eq!(list_processed_logs(None, 10).0.len(), 2);
+    }
+
+    #[test]
+    fn should_move_reprocessed_log_to_its_new_block() {
+        record_processed_log(source(0), processed_log(10, 0));
+        let previous = record_processed_log(source(0), processed_log(40, 0));
+        assert_eq!(previous, Some(processed_log(10, 0)));
+        assert_eq!(indexed_blocks(), vec![40]);
+
+        prune_processed_logs(20);
+        assert_eq!(get_processed_log(&source(0)), Some(processed_log(40, 0)));
+    }
+
+    #[test]
+    fn should_keep_job_id_reused_by_a_newer_log() {
+        record_processed_log(source(0), processed_log(10, 7));
+        record_processed_log(source(1), processed_log(30, 7));
+
+        prune_processed_logs(20);
+
+        assert_eq!(
+            get_processed_log_by_job_id(B256::with_last_byte(7).0),
+            Some((source(1), processed_log(30, 7)))
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Fixtures shared by the unit tests.
+
+use alloy::primitives::B256;
+use alloy::rpc::types::Log;
+use alloy::transports::icp::RpcService;
+use ic_cdk::api::management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId};
+
+use crate::lifecycle::InitArg;
+use crate::state::State;
+
+pub fn init_arg() -> InitArg {
+    InitArg {
+        rpc_service: RpcService::Chain(31337),
+        rpc_services: None,
+        consensus: None,
+        chain_id: 31337,
+        filter_addresses: vec!["0x5FbDB2315678afecb367f032d93F642f64180aa3".to_string()],
+        coprocessor_evm_address: "0x5FbDB2315678afecb367f032d93F642f64180aa3".to_string(),
+        filter_events: vec!["NewJob(uint256,uint256)".to_string()],
+        filter_topics: None,
+        ecdsa_key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: "dfx_test_key".to_string(),
+        },
+        processed_logs_retention_blocks: None,
+        start_block: None,
+        finality: None,
+        callback_signature: None,
+        stuck_transaction_timeout_secs: None,
+        max_concurrent_jobs: None,
+        batch: None,
+        polling_interval: None,
+    }
+}
+
+pub fn state() -> State {
+    State::try_from(init_arg()).expect("invalid init arg")
+}
+
+/// A log of block 100 from the transaction `0x0202…02`.
+pub fn log(log_index: u64) -> Log {
+    Log {
+        block_hash: Some(B256::repeat_byte(1)),
+        block_number: Some(100),
+        transaction_hash: Some(B256::repeat_byte(2)),
+        log_index: Some(log_index),
+        ..Default::default()
+    }
+}
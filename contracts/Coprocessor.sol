@@ -11,10 +11,10 @@ contract Coprocessor {
 
     mapping(uint => string) public jobs;
 
-    event NewJob(uint indexed job_id);
+    event NewJob(uint indexed job_id, uint n);
 
-    // Function to create a new job
-    function newJob() public payable {
+    // Function to create a new job computing the `_n`th Fibonacci number
+    function newJob(uint _n) public payable {
         // Require at least 0.01 ETH to be sent with the call
         require(msg.value >= 0.01 ether, "Minimum 0.01 ETH not met");
 
@@ -23,8 +23,9 @@ contract Coprocessor {
         // contract.
         coprocessor.transfer(msg.value);
 
-        // Emit the new job event
-        emit NewJob(job_id);
+        // Emit the new job event, the job input is passed to the coprocessor
+        // as part of the event
+        emit NewJob(job_id, _n);
 
         // Increment job counter
         job_id++;
@@ -42,6 +43,25 @@ contract Coprocessor {
         jobs[_job_id] = _result;
     }
 
+    // Stores the results of several jobs sent in a single transaction. Every
+    // result is ABI-encoded as the `_result` argument of `callback`.
+    function callbackBatch(
+        uint256[] calldata _job_ids,
+        bytes[] calldata _results
+    ) public {
+        require(
+            msg.sender == coprocessor,
+            "Only the coprocessor can call this function"
+        );
+        require(
+            _job_ids.length == _results.length,
+            "Job ids and results must have the same length"
+        );
+        for (uint i = 0; i < _job_ids.length; i++) {
+            jobs[_job_ids[i]] = abi.decode(_results[i], (string));
+        }
+    }
+
     function updateCoprocessor(address _coprocessor) public {
         require(
             msg.sender == coprocessor,
@@ -17,9 +17,9 @@ contract MyScript is Script {
         // this creates the contract. it will have the same address every time if we use a
         // new instance of anvil for every deployment.
 
-        // we create 3 jobs
+        // we create 3 jobs computing the 20th, 21st and 22nd Fibonacci number
         for (uint256 index = 0; index < 3; index++) {
-            coprocessor.newJob{value: 0.1 ether}();
+            coprocessor.newJob{value: 0.1 ether}(20 + index);
         }
 
         vm.stopBroadcast();
@@ -3,6 +3,28 @@
 #![allow(dead_code, unused_imports, non_snake_case)]
 use candid::{self, CandidType, Decode, Deserialize, Encode, Principal};
 
+#[derive(CandidType, Deserialize)]
+pub struct LogSource {
+    pub transaction_hash: String,
+    pub log_index: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ConfigChange {
+    pub change: String,
+    pub timestamp: u64,
+    pub caller: Principal,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DeadLetterJob {
+    pub block_number: Option<u64>,
+    pub log_source: LogSource,
+    pub attempts: u32,
+    pub transaction_hash: Option<String>,
+    pub last_error: Option<String>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub enum EcdsaCurve {
     #[serde(rename = "secp256k1")]
@@ -56,14 +78,238 @@ pub enum RpcService {
     Provider(u64),
 }
 
+#[derive(CandidType, Deserialize)]
+pub enum FinalityMode {
+    Safe,
+    Finalized,
+    Latest { confirmations: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BatchConfig {
+    pub window_secs: u64,
+    pub max_size: u32,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum ConsensusStrategy {
+    Equality,
+    Threshold { total: Option<u8>, min: u8 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum PollingInterval {
+    Fixed { secs: u64 },
+    Adaptive { min_secs: u64, max_secs: u64 },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TopicFilter {
+    pub topic1: Option<Vec<String>>,
+    pub topic2: Option<Vec<String>>,
+    pub topic3: Option<Vec<String>>,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct InitArg {
     pub ecdsa_key_id: EcdsaKeyId,
     pub rpc_service: RpcService,
+    pub rpc_services: Option<Vec<RpcService>>,
+    pub consensus: Option<ConsensusStrategy>,
     pub filter_addresses: Vec<String>,
     pub chain_id: u64,
     pub coprocessor_evm_address: String,
     pub filter_events: Vec<String>,
+    pub filter_topics: Option<TopicFilter>,
+    pub processed_logs_retention_blocks: Option<u64>,
+    pub start_block: Option<u64>,
+    pub finality: Option<FinalityMode>,
+    pub callback_signature: Option<String>,
+    pub stuck_transaction_timeout_secs: Option<u64>,
+    pub max_concurrent_jobs: Option<u32>,
+    pub batch: Option<BatchConfig>,
+    pub polling_interval: Option<PollingInterval>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct UpgradeArg {
+    pub rpc_service: Option<RpcService>,
+    pub rpc_services: Option<Vec<RpcService>>,
+    pub consensus: Option<ConsensusStrategy>,
+    pub filter_addresses: Option<Vec<String>>,
+    pub chain_id: Option<u64>,
+    pub coprocessor_evm_address: Option<String>,
+    pub filter_events: Option<Vec<String>>,
+    pub filter_topics: Option<TopicFilter>,
+    pub processed_logs_retention_blocks: Option<Option<u64>>,
+    pub finality: Option<FinalityMode>,
+    pub callback_signature: Option<String>,
+    pub stuck_transaction_timeout_secs: Option<u64>,
+    pub max_concurrent_jobs: Option<u32>,
+    pub batch: Option<Option<BatchConfig>>,
+    pub polling_interval: Option<PollingInterval>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum CanisterArg {
+    UpgradeArg(UpgradeArg),
+    InitArg(InitArg),
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum Mode {
+    Running,
+    ScrapingPaused,
+    ProcessingPaused,
+    Paused,
+    Draining,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct ModeStatus {
+    pub mode: Mode,
+    pub in_flight_jobs: u64,
+    pub drained: bool,
+}
+
+pub type Result_ = std::result::Result<(), String>;
+
+#[derive(CandidType, Deserialize)]
+pub enum JobStatus {
+    Failed,
+    Submitting,
+    Confirmed,
+    Reorged,
+    Submitted,
+    Pending,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct JobInfo {
+    pub status: JobStatus,
+    pub result: Option<String>,
+    pub block_number: Option<u64>,
+    pub log_source: LogSource,
+    pub job_id: Option<candid::Nat>,
+    pub attempts: u32,
+    pub callback_transaction_hash: Option<String>,
+    pub last_error: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LogFilter {
+    pub min_level: Option<LogLevel>,
+    pub log_source: Option<LogSource>,
+    pub job_id: Option<candid::Nat>,
+    pub from: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterLogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub message: String,
+    pub log_source: Option<LogSource>,
+    pub job_id: Option<String>,
+}
+
+pub type Result3 = std::result::Result<Vec<CanisterLogEntry>, String>;
+
+pub type Result4 = std::result::Result<String, String>;
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub url: String,
+    pub method: String,
+    pub body: serde_bytes::ByteBuf,
+    pub headers: Vec<(String, String)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub body: serde_bytes::ByteBuf,
+    pub headers: Vec<(String, String)>,
+    pub status_code: u16,
+}
+
+pub type Result1 = std::result::Result<Option<JobInfo>, String>;
+
+#[derive(CandidType, Deserialize)]
+pub struct JobPage {
+    pub jobs: Vec<JobInfo>,
+    pub next: Option<LogSource>,
+}
+
+pub type Result2 = std::result::Result<JobPage, String>;
+
+#[derive(CandidType, Deserialize)]
+pub enum TaskType {
+    ProcessLogs,
+    ScrapeLogs,
+    SubmitResults,
+    TrackTransactions,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TaskError {
+    pub task: TaskType,
+    pub timestamp: u64,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BlockRange {
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CanisterStatus {
+    pub mode: Mode,
+    pub evm_address: Option<String>,
+    pub next_nonce: Option<u64>,
+    pub last_scraped_block_number: Option<u64>,
+    pub block_spread: u64,
+    pub pending_rescans: Vec<BlockRange>,
+    pub pending_jobs: u64,
+    pub active_jobs: u64,
+    pub queued_results: u64,
+    pub submitted_transactions: u64,
+    pub dead_letter_jobs: u64,
+    pub active_tasks: Vec<TaskType>,
+    pub last_errors: Vec<TaskError>,
+    pub cycle_balance: candid::Nat,
+    pub rpc_service: RpcService,
+    pub rpc_services: Vec<RpcService>,
+    pub consensus: ConsensusStrategy,
+    pub chain_id: u64,
+    pub filter_topics: TopicFilter,
+    pub polling_interval: PollingInterval,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct InconsistentResponse {
+    pub timestamp: u64,
+    pub from_block: u64,
+    pub to_block: u64,
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct EventTopic {
+    pub signature: String,
+    pub topic0: String,
+    pub has_handler: bool,
 }
 
 pub struct ChainFusionCanister {
@@ -72,6 +318,42 @@ pub struct ChainFusionCanister {
 }
 
 impl ChainFusionCanister {
+    pub fn drain(&self) -> super::CallBuilder<()> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "drain",
+            args,
+        )
+    }
+    pub fn get_config_changes(&self) -> super::CallBuilder<Vec<ConfigChange>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_config_changes",
+            args,
+        )
+    }
+    pub fn get_dead_letter_jobs(&self) -> super::CallBuilder<Vec<DeadLetterJob>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_dead_letter_jobs",
+            args,
+        )
+    }
+    pub fn get_event_topics(&self) -> super::CallBuilder<Vec<EventTopic>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_event_topics",
+            args,
+        )
+    }
     pub fn get_evm_address(&self) -> super::CallBuilder<Option<String>> {
         let args = Encode!();
         self.caller.call(
@@ -81,6 +363,213 @@ impl ChainFusionCanister {
             args,
         )
     }
+    pub fn get_inconsistent_responses(&self) -> super::CallBuilder<Vec<InconsistentResponse>> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_inconsistent_responses",
+            args,
+        )
+    }
+    pub fn get_job(&self, arg0: candid::Nat) -> super::CallBuilder<Result1> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_job",
+            args,
+        )
+    }
+    pub fn get_job_by_log_source(&self, arg0: LogSource) -> super::CallBuilder<Result1> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_job_by_log_source",
+            args,
+        )
+    }
+    pub fn get_logs(&self, arg0: LogFilter) -> super::CallBuilder<Result3> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_logs",
+            args,
+        )
+    }
+    pub fn get_mode(&self) -> super::CallBuilder<ModeStatus> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_mode",
+            args,
+        )
+    }
+    pub fn get_status(&self) -> super::CallBuilder<CanisterStatus> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "get_status",
+            args,
+        )
+    }
+    pub fn http_request(&self, arg0: HttpRequest) -> super::CallBuilder<HttpResponse> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "http_request",
+            args,
+        )
+    }
+    pub fn issue_logs_token(&self) -> super::CallBuilder<Result4> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "issue_logs_token",
+            args,
+        )
+    }
+    pub fn list_pending_jobs(&self, arg0: Option<LogSource>, arg1: u32) -> super::CallBuilder<Result2> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "list_pending_jobs",
+            args,
+        )
+    }
+    pub fn list_processed_jobs(&self, arg0: Option<LogSource>, arg1: u32) -> super::CallBuilder<Result2> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Query,
+            "list_processed_jobs",
+            args,
+        )
+    }
+    pub fn pause_processing(&self) -> super::CallBuilder<()> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "pause_processing",
+            args,
+        )
+    }
+    pub fn pause_scraping(&self) -> super::CallBuilder<()> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "pause_scraping",
+            args,
+        )
+    }
+    pub fn requeue_dead_letter_job(&self, arg0: LogSource) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "requeue_dead_letter_job",
+            args,
+        )
+    }
+    pub fn rescan(&self, arg0: u64, arg1: u64) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0, &arg1);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "rescan",
+            args,
+        )
+    }
+    pub fn resume(&self) -> super::CallBuilder<()> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "resume",
+            args,
+        )
+    }
+    pub fn revoke_logs_token(&self) -> super::CallBuilder<()> {
+        let args = Encode!();
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "revoke_logs_token",
+            args,
+        )
+    }
+    pub fn set_chain_id(&self, arg0: u64) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_chain_id",
+            args,
+        )
+    }
+    pub fn set_coprocessor_evm_address(&self, arg0: String) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_coprocessor_evm_address",
+            args,
+        )
+    }
+    pub fn set_filter_addresses(&self, arg0: Vec<String>) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_filter_addresses",
+            args,
+        )
+    }
+    pub fn set_filter_events(&self, arg0: Vec<String>) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_filter_events",
+            args,
+        )
+    }
+    pub fn set_filter_topics(&self, arg0: TopicFilter) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_filter_topics",
+            args,
+        )
+    }
+    pub fn set_polling_interval(&self, arg0: PollingInterval) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_polling_interval",
+            args,
+        )
+    }
+    pub fn set_rpc_service(&self, arg0: RpcService) -> super::CallBuilder<Result_> {
+        let args = Encode!(&arg0);
+        self.caller.call(
+            self.canister_id,
+            super::CallMode::Update,
+            "set_rpc_service",
+            args,
+        )
+    }
 }
 pub const CANISTER_ID: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 160, 190, 169, 1, 1]); // 2222s-4iaaa-aaaaf-ax2uq-cai
 
@@ -93,7 +582,7 @@ pub fn new(caller: &super::Caller, canister_id: Principal) -> ChainFusionCaniste
 
 pub fn deploy(
     deployer: &super::Deployer,
-    arg0: InitArg,
+    arg0: CanisterArg,
 ) -> super::DeployBuilder<ChainFusionCanister> {
     let args = Encode!(&arg0);
     let result = deployer.deploy(args, new);
@@ -42,7 +42,7 @@ async fn setup(test: IcpTest) -> Env {
 
     let chain_fusion = chain_fusion::deploy(
         &icp_user,
-        chain_fusion::InitArg {
+        chain_fusion::CanisterArg::InitArg(chain_fusion::InitArg {
             ecdsa_key_id: chain_fusion::EcdsaKeyId {
                 curve: chain_fusion::EcdsaCurve::Secp256K1,
                 name: "dfx_test_key".to_string(),
@@ -51,11 +51,22 @@ async fn setup(test: IcpTest) -> Env {
                 url: test.evm.rpc_url().to_string(),
                 headers: None,
             }),
+            rpc_services: None,
+            consensus: None,
             chain_id: test.evm.chain_id(),
             filter_addresses: vec![coprocessor.address().to_string()],
             coprocessor_evm_address: coprocessor.address().to_string(),
-            filter_events: vec!["NewJob(uint256)".to_string()],
-        },
+            filter_events: vec!["NewJob(uint256,uint256)".to_string()],
+            filter_topics: None,
+            processed_logs_retention_blocks: None,
+            start_block: None,
+            finality: None,
+            callback_signature: None,
+            stuck_transaction_timeout_secs: None,
+            max_concurrent_jobs: None,
+            batch: None,
+            polling_interval: None,
+        }),
     )
     .call()
     .await;
@@ -109,7 +120,7 @@ async fn test_coprocessor_job() {
     let payment = parse_ether("0.1").unwrap();
 
     let receipt = coprocessor
-        .newJob()
+        .newJob(U256::from(20))
         .value(payment)
         .send()
         .await